@@ -0,0 +1,760 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::func::{get_active_slot_suffix, slot_partition_path};
+
+const AVB_MAGIC: &[u8; 4] = b"AVB0";
+const VBMETA_HEADER_SIZE: usize = 256;
+const FLAGS_OFFSET: u64 = 120;
+
+pub const AVB_FLAG_HASHTREE_DISABLED: u32 = 1;
+pub const AVB_FLAG_VERIFICATION_DISABLED: u32 = 2;
+const AVB_FLAGS_MASK: u32 = AVB_FLAG_HASHTREE_DISABLED | AVB_FLAG_VERIFICATION_DISABLED;
+
+const VBMETA_PARTITIONS: [&str; 3] = ["vbmeta", "vbmeta_system", "vbmeta_vendor"];
+
+/// Reads the big-endian `flags` field of the vbmeta header at the start of `path`.
+pub fn read_flags(path: &str) -> Result<u32> {
+    let mut file = File::open(path).context(format!("Failed to open {}", path))?;
+    let mut header = [0u8; VBMETA_HEADER_SIZE];
+    file.read_exact(&mut header).context(format!("{} is too small for a vbmeta header", path))?;
+    if &header[..4] != AVB_MAGIC {
+        bail!("{} is not a vbmeta image (no AVB0 magic)", path);
+    }
+    let at = FLAGS_OFFSET as usize;
+    Ok(u32::from_be_bytes(header[at..at + 4].try_into().unwrap()))
+}
+
+/// Replaces the AVB disable bits of the vbmeta header in `path` with `flags`,
+/// keeping any other bits. Returns the old and new flags.
+pub fn write_flags(path: &str, flags: u32) -> Result<(u32, u32)> {
+    let old = read_flags(path)?;
+    let new = (old & !AVB_FLAGS_MASK) | (flags & AVB_FLAGS_MASK);
+    if new != old {
+        let file = OpenOptions::new().write(true).open(path).context(format!("Failed to open {}", path))?;
+        file.write_all_at(&new.to_be_bytes(), FLAGS_OFFSET)?;
+        file.sync_all()?;
+    }
+    Ok((old, new))
+}
+
+fn describe(flags: u32) -> String {
+    format!(
+        "verity {}, verification {}",
+        if flags & AVB_FLAG_HASHTREE_DISABLED != 0 { "disabled" } else { "enabled" },
+        if flags & AVB_FLAG_VERIFICATION_DISABLED != 0 { "disabled" } else { "enabled" }
+    )
+}
+
+/// `patch_vbmeta_flags <image> <flags>`: sets the AVB flags of a vbmeta image
+/// or partition. `flags` is 0-3 (1 = disable verity, 2 = disable verification).
+pub fn patch_vbmeta_flags(ui: &mut RecoveryUI, path: &str, flags: &str) -> Result<()> {
+    let flags: u32 = flags.parse().context(format!("Invalid vbmeta flags: {}", flags))?;
+    if flags & !AVB_FLAGS_MASK != 0 {
+        bail!("Invalid vbmeta flags: {} (expected 0-3)", flags);
+    }
+    let (old, new) = write_flags(path, flags)?;
+    ui.ui_print(&format!("{}: flags {} -> {} ({})", path, old, new, describe(new)))?;
+    Ok(())
+}
+
+/// Disables verity and verification on `vbmeta`, `vbmeta_system` and
+/// `vbmeta_vendor` of the current slot by patching their headers in place.
+pub fn disable_vbmeta(ui: &mut RecoveryUI) -> Result<()> {
+    let suffix = get_active_slot_suffix().unwrap_or_default();
+
+    ui.ui_print("Checking avb vbmeta status")?;
+    let mut patched = 0;
+    for name in VBMETA_PARTITIONS {
+        let Some(path) = [slot_partition_path(name, &suffix), slot_partition_path(name, "")]
+            .into_iter()
+            .find(|p| Path::new(p).exists())
+        else {
+            continue;
+        };
+
+        let flags = read_flags(&path)?;
+        if flags & AVB_FLAGS_MASK == AVB_FLAGS_MASK {
+            ui.ui_print(&format!("- {}: already disabled", path))?;
+        } else {
+            write_flags(&path, AVB_FLAGS_MASK)?;
+            ui.ui_print(&format!("- {}: {} -> disabled", path, describe(flags)))?;
+        }
+        patched += 1;
+    }
+
+    if patched == 0 {
+        let msg = "No vbmeta partition found. Aborting...";
+        let _ = ui.ui_print(msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
+
+const AVB_FOOTER_MAGIC: &[u8; 4] = b"AVBf";
+const AVB_FOOTER_SIZE: usize = 64;
+
+const DESCRIPTOR_TAG_HASHTREE: u64 = 1;
+const DESCRIPTOR_TAG_HASH: u64 = 2;
+const DESCRIPTOR_TAG_CHAIN_PARTITION: u64 = 4;
+
+/// Fixed part of each descriptor after the 16-byte tag/length header.
+const HASH_DESCRIPTOR_SIZE: usize = 116;
+const HASHTREE_DESCRIPTOR_SIZE: usize = 164;
+const CHAIN_DESCRIPTOR_SIZE: usize = 76;
+
+#[derive(Clone, Copy)]
+enum AvbHash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl AvbHash {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sha1" => Ok(AvbHash::Sha1),
+            "sha256" => Ok(AvbHash::Sha256),
+            "sha512" => Ok(AvbHash::Sha512),
+            _ => bail!("unsupported hash algorithm {}", name),
+        }
+    }
+
+    fn digest(&self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            AvbHash::Sha1 => digest_parts::<Sha1>(parts),
+            AvbHash::Sha256 => digest_parts::<Sha256>(parts),
+            AvbHash::Sha512 => digest_parts::<Sha512>(parts),
+        }
+    }
+
+    fn digest_reader<R: Read>(&self, salt: &[u8], reader: &mut R, len: u64) -> Result<Vec<u8>> {
+        match self {
+            AvbHash::Sha1 => digest_reader::<Sha1, R>(salt, reader, len),
+            AvbHash::Sha256 => digest_reader::<Sha256, R>(salt, reader, len),
+            AvbHash::Sha512 => digest_reader::<Sha512, R>(salt, reader, len),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            AvbHash::Sha1 => 20,
+            AvbHash::Sha256 => 32,
+            AvbHash::Sha512 => 64,
+        }
+    }
+}
+
+fn digest_parts<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = D::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Hashes `salt` followed by exactly `len` bytes of `reader`.
+fn digest_reader<D: Digest, R: Read>(salt: &[u8], reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut hasher = D::new();
+    hasher.update(salt);
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = std::cmp::min(remaining, buffer.len() as u64) as usize;
+        let n = reader.read(&mut buffer[..want])?;
+        if n == 0 {
+            bail!("image is {} bytes smaller than the size in its descriptor", remaining);
+        }
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+fn field(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .context("vbmeta is truncated")
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(field(data, offset, 4)?.try_into().unwrap()))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_be_bytes(field(data, offset, 8)?.try_into().unwrap()))
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+enum Descriptor {
+    Hash { partition: String, image_size: u64, hash: AvbHash, salt: Vec<u8>, digest: Vec<u8> },
+    Hashtree {
+        partition: String,
+        image_size: u64,
+        data_block_size: usize,
+        hash_block_size: usize,
+        hash: AvbHash,
+        salt: Vec<u8>,
+        root_digest: Vec<u8>,
+    },
+    Chain { partition: String, public_key: Vec<u8> },
+}
+
+/// A parsed vbmeta blob: its public key and the descriptors we know how to check.
+struct VbMeta {
+    public_key: Vec<u8>,
+    descriptors: Vec<Descriptor>,
+}
+
+impl VbMeta {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if field(data, 0, 4)? != AVB_MAGIC {
+            bail!("no AVB0 magic");
+        }
+        let auth_size = be_u64(data, 12)? as usize;
+        let aux_size = be_u64(data, 20)? as usize;
+        let algorithm = be_u32(data, 28)?;
+        let header = field(data, 0, VBMETA_HEADER_SIZE)?;
+        let auth = field(data, VBMETA_HEADER_SIZE, auth_size)?;
+        let aux = field(data, VBMETA_HEADER_SIZE + auth_size, aux_size)?;
+
+        // SHA256_RSA* are 1-3, SHA512_RSA* are 4-6; 0 means unsigned.
+        let hash_alg = match algorithm {
+            0 => None,
+            1..=3 => Some(AvbHash::Sha256),
+            4..=6 => Some(AvbHash::Sha512),
+            _ => bail!("unknown algorithm type {}", algorithm),
+        };
+        if let Some(alg) = hash_alg {
+            let expected = field(auth, be_u64(data, 32)? as usize, be_u64(data, 40)? as usize)?;
+            if alg.digest(&[header, aux]) != expected {
+                bail!("vbmeta hash doesn't match its header and descriptors");
+            }
+        }
+
+        let public_key = field(aux, be_u64(data, 64)? as usize, be_u64(data, 72)? as usize)?.to_vec();
+        let descriptors_data = field(aux, be_u64(data, 96)? as usize, be_u64(data, 104)? as usize)?;
+
+        let mut descriptors = Vec::new();
+        let mut pos = 0;
+        while pos + 16 <= descriptors_data.len() {
+            let tag = be_u64(descriptors_data, pos)?;
+            let len = be_u64(descriptors_data, pos + 8)? as usize;
+            let body = field(descriptors_data, pos + 16, len)?;
+            if let Some(descriptor) = parse_descriptor(tag, body)? {
+                descriptors.push(descriptor);
+            }
+            pos += 16 + len;
+        }
+        Ok(VbMeta { public_key, descriptors })
+    }
+}
+
+fn parse_descriptor(tag: u64, body: &[u8]) -> Result<Option<Descriptor>> {
+    match tag {
+        DESCRIPTOR_TAG_HASH => {
+            let image_size = be_u64(body, 0)?;
+            let hash = AvbHash::from_name(&c_string(field(body, 8, 32)?))?;
+            let name_len = be_u32(body, 40)? as usize;
+            let salt_len = be_u32(body, 44)? as usize;
+            let digest_len = be_u32(body, 48)? as usize;
+            let mut pos = HASH_DESCRIPTOR_SIZE;
+            let partition = String::from_utf8_lossy(field(body, pos, name_len)?).into_owned();
+            pos += name_len;
+            let salt = field(body, pos, salt_len)?.to_vec();
+            pos += salt_len;
+            let digest = field(body, pos, digest_len)?.to_vec();
+            Ok(Some(Descriptor::Hash { partition, image_size, hash, salt, digest }))
+        },
+        DESCRIPTOR_TAG_HASHTREE => {
+            let image_size = be_u64(body, 4)?;
+            let data_block_size = be_u32(body, 28)? as usize;
+            let hash_block_size = be_u32(body, 32)? as usize;
+            let hash = AvbHash::from_name(&c_string(field(body, 56, 32)?))?;
+            let name_len = be_u32(body, 88)? as usize;
+            let salt_len = be_u32(body, 92)? as usize;
+            let digest_len = be_u32(body, 96)? as usize;
+            if data_block_size == 0 || hash_block_size == 0 {
+                bail!("hashtree descriptor has a zero block size");
+            }
+            let mut pos = HASHTREE_DESCRIPTOR_SIZE;
+            let partition = String::from_utf8_lossy(field(body, pos, name_len)?).into_owned();
+            pos += name_len;
+            let salt = field(body, pos, salt_len)?.to_vec();
+            pos += salt_len;
+            let root_digest = field(body, pos, digest_len)?.to_vec();
+            Ok(Some(Descriptor::Hashtree {
+                partition, image_size, data_block_size, hash_block_size, hash, salt, root_digest,
+            }))
+        },
+        DESCRIPTOR_TAG_CHAIN_PARTITION => {
+            let name_len = be_u32(body, 4)? as usize;
+            let key_len = be_u32(body, 8)? as usize;
+            let pos = CHAIN_DESCRIPTOR_SIZE;
+            let partition = String::from_utf8_lossy(field(body, pos, name_len)?).into_owned();
+            let public_key = field(body, pos + name_len, key_len)?.to_vec();
+            Ok(Some(Descriptor::Chain { partition, public_key }))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// dm-verity root digest of the first `image_size` bytes of `reader`,
+/// built the same way as avbtool's `generate_hash_tree`.
+fn hashtree_root<R: Read>(
+    reader: &mut R,
+    image_size: u64,
+    data_block_size: usize,
+    hash_block_size: usize,
+    hash: AvbHash,
+    salt: &[u8],
+) -> Result<Vec<u8>> {
+    let padding = hash.size().next_power_of_two() - hash.size();
+
+    let mut level = Vec::new();
+    let mut block = vec![0u8; data_block_size];
+    let mut remaining = image_size;
+    while remaining > 0 {
+        let len = std::cmp::min(remaining, data_block_size as u64) as usize;
+        reader.read_exact(&mut block[..len]).context("image is smaller than the size in its descriptor")?;
+        block[len..].fill(0);
+        level.extend_from_slice(&hash.digest(&[salt, &block]));
+        level.resize(level.len() + padding, 0);
+        remaining -= len as u64;
+    }
+    if image_size <= data_block_size as u64 {
+        return Ok(hash.digest(&[salt, &block]));
+    }
+    level.resize(level.len().div_ceil(hash_block_size) * hash_block_size, 0);
+
+    while level.len() > hash_block_size {
+        let mut next = Vec::with_capacity(level.len() / hash_block_size * (hash.size() + padding));
+        for chunk in level.chunks(hash_block_size) {
+            next.extend_from_slice(&hash.digest(&[salt, chunk]));
+            next.resize(next.len() + padding, 0);
+        }
+        next.resize(next.len().div_ceil(hash_block_size) * hash_block_size, 0);
+        level = next;
+    }
+    Ok(hash.digest(&[salt, &level]))
+}
+
+/// Reads the vbmeta of a chained image: either a whole vbmeta image, or the
+/// blob an `AVBf` footer at the end of the image points to.
+fn read_image_vbmeta(archive: &mut ZipArchive<File>, entry_name: &str) -> Result<Vec<u8>> {
+    let mut head = [0u8; 4];
+    let mut tail = Vec::with_capacity(AVB_FOOTER_SIZE * 2);
+    {
+        let mut entry = archive.by_name(entry_name)?;
+        entry.read_exact(&mut head)?;
+        if &head == AVB_MAGIC {
+            let mut data = head.to_vec();
+            entry.read_to_end(&mut data)?;
+            return Ok(data);
+        }
+        let mut buffer = vec![0u8; 1024 * 1024];
+        loop {
+            let n = entry.read(&mut buffer)?;
+            if n == 0 { break; }
+            tail.extend_from_slice(&buffer[..n]);
+            if tail.len() > AVB_FOOTER_SIZE {
+                tail.drain(..tail.len() - AVB_FOOTER_SIZE);
+            }
+        }
+    }
+
+    if tail.len() < AVB_FOOTER_SIZE || &tail[..4] != AVB_FOOTER_MAGIC {
+        bail!("no vbmeta image or AVB footer found");
+    }
+    let vbmeta_offset = be_u64(&tail, 20)?;
+    let vbmeta_size = be_u64(&tail, 28)?;
+
+    let mut entry = archive.by_name(entry_name)?;
+    std::io::copy(&mut (&mut entry).take(vbmeta_offset), &mut std::io::sink())?;
+    let mut data = Vec::with_capacity(vbmeta_size as usize);
+    entry.take(vbmeta_size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// `boot.img` -> `boot`.
+fn partition_name(entry_name: &str) -> String {
+    let file = entry_name.rsplit('/').next().unwrap_or(entry_name);
+    file.strip_suffix(".img").unwrap_or(file).to_string()
+}
+
+/// Checks every descriptor in `vbmeta` that has an image in `images`,
+/// following chain descriptors into the chained images' own vbmeta.
+fn verify_descriptors(
+    ui: &mut RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    vbmeta: &VbMeta,
+    images: &HashMap<String, String>,
+    checked: &mut HashSet<String>,
+    failures: &mut Vec<String>,
+) -> Result<()> {
+    for descriptor in &vbmeta.descriptors {
+        let partition = match descriptor {
+            Descriptor::Hash { partition, .. }
+            | Descriptor::Hashtree { partition, .. }
+            | Descriptor::Chain { partition, .. } => partition,
+        };
+        let Some(entry_name) = images.get(partition) else { continue; };
+        if !checked.insert(partition.clone()) { continue; }
+
+        let result = match descriptor {
+            Descriptor::Hash { image_size, hash, salt, digest, .. } => {
+                let mut entry = archive.by_name(entry_name)?;
+                hash.digest_reader(salt, &mut entry, *image_size).map(|actual| actual == *digest)
+            },
+            Descriptor::Hashtree { image_size, data_block_size, hash_block_size, hash, salt, root_digest, .. } => {
+                let mut entry = archive.by_name(entry_name)?;
+                hashtree_root(&mut entry, *image_size, *data_block_size, *hash_block_size, *hash, salt)
+                    .map(|actual| actual == *root_digest)
+            },
+            Descriptor::Chain { public_key, .. } => {
+                match read_image_vbmeta(archive, entry_name).and_then(|data| VbMeta::parse(&data)) {
+                    Ok(chained) if chained.public_key == *public_key => {
+                        // The chained vbmeta usually describes the image it is appended to.
+                        let mut own = HashMap::new();
+                        own.insert(partition.clone(), entry_name.clone());
+                        let mut own_checked = HashSet::new();
+                        verify_descriptors(ui, archive, &chained, &own, &mut own_checked, failures)?;
+                        verify_descriptors(ui, archive, &chained, images, checked, failures)?;
+                        Ok(true)
+                    },
+                    Ok(_) => Err(anyhow::anyhow!("signed with a different key than vbmeta expects")),
+                    Err(e) => Err(e),
+                }
+            },
+        };
+
+        let what = if matches!(descriptor, Descriptor::Chain { .. }) { "chain key" } else { "digest" };
+        match result {
+            Ok(true) => ui.ui_print(&format!("- {}: {} OK", entry_name, what))?,
+            Ok(false) => failures.push(format!("{}: digest doesn't match vbmeta", entry_name)),
+            Err(e) => failures.push(format!("{}: {:#}", entry_name, e)),
+        }
+    }
+    Ok(())
+}
+
+/// `verify_avb <vbmeta entry> [image entries...]`: checks zip images against
+/// the hash, hashtree and chain descriptors of a vbmeta image before anything
+/// is flashed. Without image arguments, every `<name>.img` next to the vbmeta
+/// entry that has a descriptor is checked.
+pub fn verify_avb(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, vbmeta_entry: &str, image_entries: &[String]) -> Result<()> {
+    ui.ui_print(&format!("Verifying images against {}...", vbmeta_entry))?;
+
+    let data = {
+        let mut entry = archive.by_name(vbmeta_entry).context(format!("{} not found in ZIP", vbmeta_entry))?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        data
+    };
+    let vbmeta = match VbMeta::parse(&data) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("{} is not a valid vbmeta image: {:#}. Aborting...", vbmeta_entry, e);
+            let _ = ui.ui_print(&msg);
+            bail!("{}", msg);
+        }
+    };
+
+    let images: HashMap<String, String> = if image_entries.is_empty() {
+        let dir = vbmeta_entry.rsplit_once('/').map(|(d, _)| format!("{}/", d)).unwrap_or_default();
+        archive
+            .file_names()
+            .filter(|n| n.starts_with(&dir) && !n[dir.len()..].contains('/') && n.ends_with(".img") && *n != vbmeta_entry)
+            .map(|n| (partition_name(n), n.to_string()))
+            .collect()
+    } else {
+        image_entries.iter().map(|n| (partition_name(n), n.clone())).collect()
+    };
+
+    let mut checked = HashSet::new();
+    let mut failures = Vec::new();
+    verify_descriptors(ui, archive, &vbmeta, &images, &mut checked, &mut failures)?;
+
+    for entry_name in image_entries {
+        if !checked.contains(&partition_name(entry_name)) {
+            failures.push(format!("{}: no descriptor for it in {}", entry_name, vbmeta_entry));
+        }
+    }
+
+    if !failures.is_empty() {
+        for msg in &failures {
+            ui.ui_print(&format!("- {}", msg))?;
+        }
+        let msg = format!("{} image(s) don't match {}. Aborting...", failures.len(), vbmeta_entry);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("rf-avb-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn flags_live_at_offset_120() {
+        let path = temp_path("flags");
+        let mut image = vec![0x5au8; 4096];
+        image[..4].copy_from_slice(AVB_MAGIC);
+        image[120..124].copy_from_slice(&0x0000_0100u32.to_be_bytes());
+        std::fs::write(&path, &image).unwrap();
+
+        assert_eq!(read_flags(&path).unwrap(), 0x100);
+        assert_eq!(write_flags(&path, AVB_FLAG_HASHTREE_DISABLED | AVB_FLAG_VERIFICATION_DISABLED).unwrap(), (0x100, 0x103));
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written[120..124], [0, 0, 0x01, 0x03]);
+        assert_eq!(written[..120], image[..120]);
+        assert_eq!(written[124..], image[124..]);
+
+        // Only the disable bits are taken from `flags`.
+        assert_eq!(write_flags(&path, 0xfffc).unwrap(), (0x103, 0x100));
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flags_need_a_vbmeta_header() {
+        let path = temp_path("not-vbmeta");
+        std::fs::write(&path, vec![0u8; 4096]).unwrap();
+        assert!(read_flags(&path).is_err());
+        assert!(write_flags(&path, AVB_FLAG_VERIFICATION_DISABLED).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0u8; 4096]);
+
+        let mut short = AVB_MAGIC.to_vec();
+        short.resize(100, 0);
+        std::fs::write(&path, short).unwrap();
+        assert!(read_flags(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn pad8(data: &mut Vec<u8>) {
+        data.resize(data.len().div_ceil(8) * 8, 0);
+    }
+
+    fn algorithm_name(name: &str) -> [u8; 32] {
+        let mut field = [0u8; 32];
+        field[..name.len()].copy_from_slice(name.as_bytes());
+        field
+    }
+
+    /// A descriptor as avbtool lays it out: tag, padded length, `fixed`,
+    /// then `tail` padded to 8 bytes.
+    fn descriptor(tag: u64, fixed: &[u8], tail: &[&[u8]]) -> Vec<u8> {
+        let mut body = fixed.to_vec();
+        for part in tail {
+            body.extend_from_slice(part);
+        }
+        pad8(&mut body);
+        let mut data = tag.to_be_bytes().to_vec();
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn hash_descriptor(partition: &str, image_size: u64, salt: &[u8], digest: &[u8]) -> Vec<u8> {
+        let mut fixed = vec![0u8; HASH_DESCRIPTOR_SIZE];
+        fixed[..8].copy_from_slice(&image_size.to_be_bytes());
+        fixed[8..40].copy_from_slice(&algorithm_name("sha256"));
+        fixed[40..44].copy_from_slice(&(partition.len() as u32).to_be_bytes());
+        fixed[44..48].copy_from_slice(&(salt.len() as u32).to_be_bytes());
+        fixed[48..52].copy_from_slice(&(digest.len() as u32).to_be_bytes());
+        descriptor(DESCRIPTOR_TAG_HASH, &fixed, &[partition.as_bytes(), salt, digest])
+    }
+
+    fn hashtree_descriptor(partition: &str, image_size: u64, salt: &[u8], root: &[u8]) -> Vec<u8> {
+        let mut fixed = vec![0u8; HASHTREE_DESCRIPTOR_SIZE];
+        fixed[..4].copy_from_slice(&1u32.to_be_bytes());
+        fixed[4..12].copy_from_slice(&image_size.to_be_bytes());
+        fixed[12..20].copy_from_slice(&image_size.to_be_bytes());
+        fixed[28..32].copy_from_slice(&4096u32.to_be_bytes());
+        fixed[32..36].copy_from_slice(&4096u32.to_be_bytes());
+        fixed[56..88].copy_from_slice(&algorithm_name("sha1"));
+        fixed[88..92].copy_from_slice(&(partition.len() as u32).to_be_bytes());
+        fixed[92..96].copy_from_slice(&(salt.len() as u32).to_be_bytes());
+        fixed[96..100].copy_from_slice(&(root.len() as u32).to_be_bytes());
+        descriptor(DESCRIPTOR_TAG_HASHTREE, &fixed, &[partition.as_bytes(), salt, root])
+    }
+
+    fn chain_descriptor(partition: &str, public_key: &[u8]) -> Vec<u8> {
+        let mut fixed = vec![0u8; CHAIN_DESCRIPTOR_SIZE];
+        fixed[..4].copy_from_slice(&1u32.to_be_bytes());
+        fixed[4..8].copy_from_slice(&(partition.len() as u32).to_be_bytes());
+        fixed[8..12].copy_from_slice(&(public_key.len() as u32).to_be_bytes());
+        descriptor(DESCRIPTOR_TAG_CHAIN_PARTITION, &fixed, &[partition.as_bytes(), public_key])
+    }
+
+    /// A vbmeta image holding `descriptors` and `public_key`. With `signed`
+    /// it claims SHA256_RSA2048 and its auth block holds the real hash of
+    /// the header and aux block, followed by a dummy signature.
+    fn vbmeta(descriptors: &[u8], public_key: &[u8], signed: bool) -> Vec<u8> {
+        let mut aux = descriptors.to_vec();
+        aux.extend_from_slice(public_key);
+        aux.resize(aux.len().div_ceil(64) * 64, 0);
+        let auth_size = if signed { (32usize + 256).div_ceil(64) * 64 } else { 0 };
+
+        let mut header = vec![0u8; VBMETA_HEADER_SIZE];
+        header[..4].copy_from_slice(AVB_MAGIC);
+        header[4..8].copy_from_slice(&1u32.to_be_bytes());
+        header[12..20].copy_from_slice(&(auth_size as u64).to_be_bytes());
+        header[20..28].copy_from_slice(&(aux.len() as u64).to_be_bytes());
+        if signed {
+            header[28..32].copy_from_slice(&1u32.to_be_bytes());
+            header[40..48].copy_from_slice(&32u64.to_be_bytes());
+            header[48..56].copy_from_slice(&32u64.to_be_bytes());
+            header[56..64].copy_from_slice(&256u64.to_be_bytes());
+        }
+        header[64..72].copy_from_slice(&(descriptors.len() as u64).to_be_bytes());
+        header[72..80].copy_from_slice(&(public_key.len() as u64).to_be_bytes());
+        header[104..112].copy_from_slice(&(descriptors.len() as u64).to_be_bytes());
+
+        let mut auth = vec![0u8; auth_size];
+        if signed {
+            auth[..32].copy_from_slice(&Sha256::digest([header.as_slice(), &aux].concat()));
+            auth[32..288].fill(0x5c);
+        }
+        [header, auth, aux].concat()
+    }
+
+    #[test]
+    fn vbmeta_hash_covers_header_and_descriptors() {
+        let descriptors = hash_descriptor("boot", 4096, b"salt", &[0x11; 32]);
+        let image = vbmeta(&descriptors, b"key", true);
+        let parsed = VbMeta::parse(&image).unwrap();
+        assert_eq!(parsed.public_key, b"key");
+        assert_eq!(parsed.descriptors.len(), 1);
+
+        // A byte of the digest in the aux block, then one of the header.
+        let aux_start = VBMETA_HEADER_SIZE + 320;
+        for at in [aux_start + 16 + HASH_DESCRIPTOR_SIZE + 8, 120] {
+            let mut tampered = image.clone();
+            tampered[at] ^= 1;
+            let err = VbMeta::parse(&tampered).err().unwrap();
+            assert!(err.to_string().contains("doesn't match"), "{}", err);
+        }
+
+        // Unsigned images carry no hash to check.
+        let mut unsigned = vbmeta(&descriptors, b"key", false);
+        unsigned[VBMETA_HEADER_SIZE + 16 + HASH_DESCRIPTOR_SIZE + 8] ^= 1;
+        assert!(VbMeta::parse(&unsigned).is_ok());
+
+        let mut truncated = image.clone();
+        truncated.truncate(image.len() - 64);
+        assert!(VbMeta::parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn vbmeta_descriptors_are_walked_in_order() {
+        let unknown = descriptor(3, &[0u8; 8], &[b"console=ttyMSM0"]);
+        let descriptors = [
+            hash_descriptor("boot", 8192, b"boot salt", &[0x22; 32]),
+            unknown,
+            hashtree_descriptor("system", 16384, b"ht", &[0x33; 20]),
+            chain_descriptor("vbmeta_system", b"chained key"),
+        ]
+        .concat();
+        let parsed = VbMeta::parse(&vbmeta(&descriptors, b"key", true)).unwrap();
+        assert_eq!(parsed.descriptors.len(), 3);
+
+        let Descriptor::Hash { partition, image_size, hash, salt, digest } = &parsed.descriptors[0] else {
+            panic!("expected a hash descriptor");
+        };
+        assert_eq!((partition.as_str(), *image_size, salt.as_slice()), ("boot", 8192, &b"boot salt"[..]));
+        assert_eq!((hash.size(), digest.as_slice()), (32, &[0x22; 32][..]));
+
+        let Descriptor::Hashtree { partition, image_size, data_block_size, hash_block_size, hash, salt, root_digest } =
+            &parsed.descriptors[1]
+        else {
+            panic!("expected a hashtree descriptor");
+        };
+        assert_eq!((partition.as_str(), *image_size, *data_block_size, *hash_block_size), ("system", 16384, 4096, 4096));
+        assert_eq!((hash.size(), salt.as_slice(), root_digest.as_slice()), (20, &b"ht"[..], &[0x33; 20][..]));
+
+        let Descriptor::Chain { partition, public_key } = &parsed.descriptors[2] else {
+            panic!("expected a chain descriptor");
+        };
+        assert_eq!((partition.as_str(), public_key.as_slice()), ("vbmeta_system", &b"chained key"[..]));
+    }
+
+    /// Deterministic test image that differs from block to block.
+    fn test_image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i * 7 + i / 4096) % 251) as u8).collect()
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hashtree_root_matches_avbtool() {
+        // Root digests from avbtool's generate_hash_tree for the same inputs.
+        let salt: Vec<u8> = (0..16).collect();
+        let image = test_image(129 * 4096 + 100);
+        let root = hashtree_root(&mut image.as_slice(), image.len() as u64, 4096, 4096, AvbHash::Sha256, &salt).unwrap();
+        assert_eq!(hex(&root), "4b2d0fa22ab81d33785b18177656e8863c655ca7fc0ab82c1658feb7a724d073");
+
+        let image = test_image(3 * 4096 + 100);
+        let root = hashtree_root(&mut image.as_slice(), image.len() as u64, 4096, 4096, AvbHash::Sha1, &salt).unwrap();
+        assert_eq!(hex(&root), "ca799e777e0c1bf05db9617792bcdad18cec1105");
+
+        // Data past `image_size` isn't part of the tree; missing data is an error.
+        let mut longer = image.clone();
+        longer.extend_from_slice(&[0xee; 4096]);
+        let same = hashtree_root(&mut longer.as_slice(), image.len() as u64, 4096, 4096, AvbHash::Sha1, &salt).unwrap();
+        assert_eq!(same, root);
+        assert!(hashtree_root(&mut &image[..4096], image.len() as u64, 4096, 4096, AvbHash::Sha1, &salt).is_err());
+    }
+
+    #[test]
+    fn image_vbmeta_is_found_through_the_footer() {
+        let blob = vbmeta(&hash_descriptor("boot", 8192, b"s", &[0x44; 32]), b"boot key", true);
+        let mut boot = test_image(8192);
+        boot.extend_from_slice(&blob);
+        boot.resize(3 * 4096 + 4096 - AVB_FOOTER_SIZE, 0);
+        let mut footer = vec![0u8; AVB_FOOTER_SIZE];
+        footer[..4].copy_from_slice(AVB_FOOTER_MAGIC);
+        footer[4..8].copy_from_slice(&1u32.to_be_bytes());
+        footer[12..20].copy_from_slice(&8192u64.to_be_bytes());
+        footer[20..28].copy_from_slice(&8192u64.to_be_bytes());
+        footer[28..36].copy_from_slice(&(blob.len() as u64).to_be_bytes());
+        boot.extend_from_slice(&footer);
+
+        let path = temp_path("footer.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for (name, data) in [("boot.img", &boot), ("vbmeta_system.img", &blob), ("plain.img", &test_image(8192))] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(read_image_vbmeta(&mut archive, "boot.img").unwrap(), blob);
+        assert_eq!(read_image_vbmeta(&mut archive, "vbmeta_system.img").unwrap(), blob);
+        assert!(read_image_vbmeta(&mut archive, "plain.img").is_err());
+        assert_eq!(VbMeta::parse(&blob).unwrap().public_key, b"boot key");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
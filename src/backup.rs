@@ -0,0 +1,200 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+
+use crate::recovery::RecoveryUI;
+use crate::threaded_writer::ThreadedWriter;
+use crate::func::to_hex;
+
+const BACKUP_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+const BACKUP_ZSTD_LEVEL: i32 = 3;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Passes data through while hashing it and counting bytes.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    fn finish(self) -> (String, u64) {
+        (to_hex(&self.hasher.finalize()), self.len)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+fn sidecar_path(backup: &str) -> String {
+    format!("{}.sha256", backup)
+}
+
+/// Copies a partition to `dest`, optionally zstd-compressed, and writes a
+/// `<dest>.sha256` sidecar with the SHA-256 of the raw partition contents.
+pub fn backup_partition(ui: &mut RecoveryUI, device: &str, dest: &str, compress: bool) -> Result<()> {
+    ui.ui_print(&format!("Backing up {} to {}...", device, dest))?;
+
+    if let Some(parent) = Path::new(dest).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let source = File::open(device).context(format!("Failed to open {}", device))?;
+    let mut reader = HashingReader::new(BufReader::with_capacity(BACKUP_BUFFER_SIZE, source));
+    let writer = ThreadedWriter::new(dest.to_string(), BACKUP_BUFFER_SIZE);
+
+    if compress {
+        let mut encoder = zstd::stream::Encoder::new(writer, BACKUP_ZSTD_LEVEL)?;
+        io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.finish()?;
+    } else {
+        let mut writer = writer;
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+    }
+
+    let (hash, len) = reader.finish();
+    let name = Path::new(device).file_name().and_then(|n| n.to_str()).unwrap_or(device);
+    fs::write(sidecar_path(dest), format!("{}  {}\n", hash, name))?;
+
+    ui.ui_print(&format!("  {} MiB saved, sha256 {}", len / (1024 * 1024), hash))?;
+    Ok(())
+}
+
+/// Writes a backup made by `backup_partition` back to `device`. The backup is
+/// checked against its sidecar and the partition size before anything is
+/// written.
+pub fn restore_partition(ui: &mut RecoveryUI, src: &str, device: &str) -> Result<()> {
+    ui.ui_print(&format!("Restoring {} to {}...", src, device))?;
+
+    let expected = match fs::read_to_string(sidecar_path(src)) {
+        Ok(s) => Some(s.split_whitespace().next().unwrap_or("").to_lowercase()),
+        Err(_) => {
+            ui.ui_print(&format!("  Warning: {} not found, restoring without verification", sidecar_path(src)))?;
+            None
+        }
+    };
+
+    let (hash, len) = {
+        let mut reader = HashingReader::new(open_backup(src)?);
+        io::copy(&mut reader, &mut io::sink()).context(format!("Failed to read {}", src))?;
+        reader.finish()
+    };
+
+    if let Some(expected) = expected
+        && expected != hash
+    {
+        let msg = format!("Checksum mismatch for {}. Backup is corrupted! Aborting...", src);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    crate::blockdev::ensure_fits(ui, src, len, device)?;
+
+    let mut reader = open_backup(src)?;
+    let mut writer = ThreadedWriter::new(device.to_string(), BACKUP_BUFFER_SIZE);
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()?;
+
+    ui.ui_print(&format!("  {} MiB restored", len / (1024 * 1024)))?;
+    Ok(())
+}
+
+fn open_backup(src: &str) -> Result<Box<dyn Read>> {
+    let mut file = File::open(src).context(format!("Failed to open {}", src))?;
+    let mut magic = [0u8; 4];
+    let is_zstd = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+
+    let reader = BufReader::with_capacity(BACKUP_BUFFER_SIZE, file);
+    if is_zstd {
+        Ok(Box::new(zstd::stream::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("rf-backup-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    fn partition(name: &str) -> (String, Vec<u8>) {
+        let path = temp_path(name);
+        let mut data: Vec<u8> = (0..3 * 1024 * 1024 + 123).map(|i| (i % 251) as u8).collect();
+        data[1024 * 1024..2 * 1024 * 1024].fill(0);
+        fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    fn remove(paths: &[&str]) {
+        for path in paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn backup_and_restore_round_trip() {
+        let (device, data) = partition("part");
+        let device_name = Path::new(&device).file_name().unwrap().to_str().unwrap().to_string();
+        let restored = temp_path("restored");
+        let mut ui = RecoveryUI::buffered();
+
+        for (name, compress) in [("raw", false), ("zst", true)] {
+            let dest = temp_path(&format!("backup.{}", name));
+            backup_partition(&mut ui, &device, &dest, compress).unwrap();
+            let written = fs::read(&dest).unwrap();
+            if compress {
+                assert!(written.starts_with(&ZSTD_MAGIC));
+            } else {
+                assert_eq!(written, data);
+            }
+            let sidecar = fs::read_to_string(sidecar_path(&dest)).unwrap();
+            assert_eq!(sidecar, format!("{}  {}\n", to_hex(&Sha256::digest(&data)), device_name));
+
+            restore_partition(&mut ui, &dest, &restored).unwrap();
+            assert_eq!(fs::read(&restored).unwrap(), data);
+            remove(&[&dest, &sidecar_path(&dest), &restored]);
+        }
+        remove(&[&device]);
+    }
+
+    #[test]
+    fn restore_checks_the_sidecar_first() {
+        let (device, data) = partition("tampered-part");
+        let dest = temp_path("tampered.img");
+        let target = temp_path("tampered-target");
+        let mut ui = RecoveryUI::buffered();
+        backup_partition(&mut ui, &device, &dest, false).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[2 * 1024 * 1024 + 5] ^= 1;
+        fs::write(&dest, &corrupted).unwrap();
+        fs::write(&target, b"untouched").unwrap();
+        let err = restore_partition(&mut ui, &dest, &target).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+        assert_eq!(fs::read(&target).unwrap(), b"untouched");
+
+        // Without a sidecar there is nothing to check against.
+        fs::remove_file(sidecar_path(&dest)).unwrap();
+        restore_partition(&mut ui, &dest, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), corrupted);
+        let output = String::from_utf8(ui.take_buffer()).unwrap();
+        assert!(output.contains("restoring without verification"));
+        remove(&[&device, &dest, &target]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
+use anyhow::{Context, Result, bail};
+
+use crate::recovery::RecoveryUI;
+
+const IOC_READ: libc::c_ulong = 2;
+
+const fn io(ty: u8, nr: u8) -> libc::c_ulong {
+    ((ty as libc::c_ulong) << 8) | nr as libc::c_ulong
+}
+
+const fn ior(ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    (IOC_READ << 30) | ((size as libc::c_ulong) << 16) | ((ty as libc::c_ulong) << 8) | nr as libc::c_ulong
+}
+
+const BLKGETSIZE64: libc::c_ulong = ior(0x12, 114, std::mem::size_of::<libc::size_t>());
+const BLKDISCARD: libc::c_ulong = io(0x12, 119);
+const BLKZEROOUT: libc::c_ulong = io(0x12, 127);
+
+/// How sparse holes (DONT_CARE and zero FILL chunks) are handled on block devices.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum HoleMode {
+    /// Seek over the range, leaving whatever was there before.
+    #[default]
+    Seek,
+    /// Issue `BLKDISCARD`. Fast, but the range may not read back as zeros.
+    Discard,
+    /// Issue `BLKZEROOUT`. The range is guaranteed to read back as zeros.
+    ZeroOut,
+}
+
+pub fn is_block_device(path: &str) -> bool {
+    fs::metadata(path)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Returns the size in bytes of the block device at `path`, or `None` if
+/// `path` is not a block device.
+pub fn device_size(path: &str) -> Result<Option<u64>> {
+    if !is_block_device(path) {
+        return Ok(None);
+    }
+
+    let file = File::open(path).context(format!("Failed to open {}", path))?;
+    let mut size: u64 = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) };
+    if ret != 0 {
+        bail!("BLKGETSIZE64 failed on {}: {}", path, std::io::Error::last_os_error());
+    }
+    Ok(Some(size))
+}
+
+/// Aborts if `needed` bytes do not fit into the block device at `dest`.
+/// Does nothing for regular files.
+pub fn ensure_fits(ui: &mut RecoveryUI, source: &str, needed: u64, dest: &str) -> Result<()> {
+    let Some(available) = device_size(dest)? else { return Ok(()); };
+
+    if needed > available {
+        let msg = format!(
+            "{} ({} bytes) does not fit in {} ({} bytes). Aborting...",
+            source, needed, dest, available
+        );
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
+
+fn range_ioctl(file: &File, request: libc::c_ulong, start: u64, len: u64) -> io::Result<()> {
+    let range: [u64; 2] = [start, len];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request as _, &range) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn discard(file: &File, start: u64, len: u64) -> io::Result<()> {
+    range_ioctl(file, BLKDISCARD, start, len)
+}
+
+pub fn zero_out(file: &File, start: u64, len: u64) -> io::Result<()> {
+    range_ioctl(file, BLKZEROOUT, start, len)
+}
+
+/// Discards the whole block device at `path` before it gets flashed, so no
+/// data from the previous ROM is left behind. Does nothing for regular files.
+pub fn discard_all(ui: &mut RecoveryUI, path: &str) -> Result<()> {
+    let Some(size) = device_size(path)? else { return Ok(()); };
+
+    ui.ui_print(&format!("  Discarding {}...", path))?;
+    let file = OpenOptions::new().write(true).open(path).context(format!("Failed to open {}", path))?;
+    if let Err(e) = discard(&file, 0, size) {
+        ui.ui_print(&format!("  Warning: discard not supported on {} ({}), zeroing instead", path, e))?;
+        zero_out(&file, 0, size).context(format!("Failed to zero out {}", path))?;
+    }
+    Ok(())
+}
+
+/// Bytes seen and bytes left untouched by differential flashing.
+#[derive(Default, Clone, Copy)]
+pub struct DiffStats {
+    pub total: u64,
+    pub skipped: u64,
+}
+
+impl DiffStats {
+    pub fn summary(&self) -> String {
+        const MIB: f64 = 1024.0 * 1024.0;
+        format!(
+            "  Unchanged: {:.1} MiB of {:.1} MiB skipped",
+            self.skipped as f64 / MIB,
+            self.total as f64 / MIB
+        )
+    }
+}
+
+/// Writes `data` at `pos` only if it differs from what is already there.
+/// Ranges that can't be read back (e.g. past the end of a file) count as changed.
+pub fn write_if_changed(file: &File, pos: u64, data: &[u8], cmp: &mut Vec<u8>, stats: &mut DiffStats) -> io::Result<()> {
+    stats.total += data.len() as u64;
+    cmp.resize(data.len(), 0);
+    if file.read_exact_at(cmp, pos).is_ok() && cmp[..] == *data {
+        stats.skipped += data.len() as u64;
+        return Ok(());
+    }
+    file.write_all_at(data, pos)
+}
@@ -0,0 +1,335 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+
+use crate::recovery::RecoveryUI;
+use crate::func::{exec_capture, get_active_slot_suffix};
+
+const MISC_PATHS: [&str; 2] = [
+    "/dev/block/by-name/misc",
+    "/dev/block/bootdevice/by-name/misc",
+];
+
+/// `offsetof(bootloader_message_ab, slot_suffix)`, where AOSP keeps
+/// `bootloader_control`.
+const BOOT_CTRL_OFFSET: u64 = 2048;
+const BOOT_CTRL_SIZE: usize = 32;
+const BOOT_CTRL_MAGIC: u32 = 0x42414342;
+const BOOT_CTRL_VERSION: u8 = 1;
+const SLOT_INFO_OFFSET: usize = 12;
+const CRC_OFFSET: usize = 28;
+
+const ACTIVE_PRIORITY: u8 = 15;
+const ACTIVE_TRIES: u8 = 6;
+
+struct SlotMetadata {
+    priority: u8,
+    tries_remaining: u8,
+    successful_boot: bool,
+    verity_corrupted: bool,
+}
+
+/// AOSP `bootloader_control` A/B metadata as stored in the misc partition.
+struct BootControl {
+    raw: [u8; BOOT_CTRL_SIZE],
+}
+
+impl BootControl {
+    fn parse(raw: [u8; BOOT_CTRL_SIZE]) -> Result<Self> {
+        let magic = u32::from_le_bytes(raw[4..8].try_into().unwrap());
+        if magic != BOOT_CTRL_MAGIC {
+            bail!("bad bootloader_control magic {:08x}", magic);
+        }
+        if raw[8] != BOOT_CTRL_VERSION {
+            bail!("unsupported bootloader_control version {}", raw[8]);
+        }
+        let stored = u32::from_le_bytes(raw[CRC_OFFSET..].try_into().unwrap());
+        if stored != crc32fast::hash(&raw[..CRC_OFFSET]) {
+            bail!("bootloader_control CRC32 mismatch");
+        }
+        let ctrl = BootControl { raw };
+        if ctrl.nb_slot() == 0 || ctrl.nb_slot() > 4 {
+            bail!("invalid slot count {}", ctrl.nb_slot());
+        }
+        Ok(ctrl)
+    }
+
+    fn nb_slot(&self) -> usize {
+        (self.raw[9] & 0x07) as usize
+    }
+
+    fn slot(&self, i: usize) -> SlotMetadata {
+        let b0 = self.raw[SLOT_INFO_OFFSET + i * 2];
+        let b1 = self.raw[SLOT_INFO_OFFSET + i * 2 + 1];
+        SlotMetadata {
+            priority: b0 & 0x0f,
+            tries_remaining: (b0 >> 4) & 0x07,
+            successful_boot: b0 & 0x80 != 0,
+            verity_corrupted: b1 & 0x01 != 0,
+        }
+    }
+
+    fn set_slot(&mut self, i: usize, slot: &SlotMetadata) {
+        let off = SLOT_INFO_OFFSET + i * 2;
+        self.raw[off] = (slot.priority & 0x0f)
+            | ((slot.tries_remaining & 0x07) << 4)
+            | if slot.successful_boot { 0x80 } else { 0 };
+        self.raw[off + 1] = (self.raw[off + 1] & !0x01) | slot.verity_corrupted as u8;
+    }
+
+    /// Makes `index` the slot to boot like AOSP `SetActiveBootSlot`: highest
+    /// priority, full tries, every other slot demoted below it. A slot we
+    /// switch to gets a fresh verity state.
+    fn activate(&mut self, index: usize, switching: bool) {
+        for i in 0..self.nb_slot() {
+            let mut other = self.slot(i);
+            if i != index && other.priority >= ACTIVE_PRIORITY {
+                other.priority = ACTIVE_PRIORITY - 1;
+                self.set_slot(i, &other);
+            }
+        }
+
+        let mut target = self.slot(index);
+        target.priority = ACTIVE_PRIORITY;
+        target.tries_remaining = ACTIVE_TRIES;
+        if switching {
+            target.verity_corrupted = false;
+        }
+        self.set_slot(index, &target);
+    }
+
+    fn mark_successful(&mut self, index: usize) {
+        let mut target = self.slot(index);
+        target.successful_boot = true;
+        // Like boot_control's markBootSuccessful: a successful slot keeps one try.
+        target.tries_remaining = 1;
+        self.set_slot(index, &target);
+    }
+
+    fn update_crc(&mut self) {
+        let crc = crc32fast::hash(&self.raw[..CRC_OFFSET]);
+        self.raw[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+fn find_misc() -> Option<&'static str> {
+    MISC_PATHS.into_iter().find(|p| Path::new(p).exists())
+}
+
+fn load() -> Result<(&'static str, BootControl)> {
+    let misc = find_misc().context("misc partition not found")?;
+    Ok((misc, read_ctrl(misc)?))
+}
+
+fn read_ctrl(misc: &str) -> Result<BootControl> {
+    let mut file = File::open(misc).context(format!("Failed to open {}", misc))?;
+    file.seek(SeekFrom::Start(BOOT_CTRL_OFFSET))?;
+    let mut raw = [0u8; BOOT_CTRL_SIZE];
+    file.read_exact(&mut raw)?;
+    BootControl::parse(raw)
+}
+
+fn save(misc: &str, ctrl: &mut BootControl) -> Result<()> {
+    ctrl.update_crc();
+    let mut file = OpenOptions::new().write(true).open(misc).context(format!("Failed to open {}", misc))?;
+    file.seek(SeekFrom::Start(BOOT_CTRL_OFFSET))?;
+    file.write_all(&ctrl.raw)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Parses `0`/`1`, `a`/`b` or `_a`/`_b` into a slot index.
+fn parse_slot(slot: &str) -> Result<usize> {
+    let s = slot.trim().trim_start_matches('_');
+    match s {
+        "a" | "A" => Ok(0),
+        "b" | "B" => Ok(1),
+        _ => s.parse().context(format!("Invalid slot: {}", slot)),
+    }
+}
+
+fn slot_suffix(i: usize) -> String {
+    format!("_{}", (b'a' + i as u8) as char)
+}
+
+fn current_slot() -> Option<usize> {
+    get_active_slot_suffix().ok().and_then(|s| parse_slot(&s).ok())
+}
+
+/// Marks `slot` active, see `BootControl::activate`.
+pub fn set_slot(ui: &mut RecoveryUI, slot: &str) -> Result<()> {
+    let index = parse_slot(slot)?;
+    let (misc, mut ctrl) = match load() {
+        Ok(c) => c,
+        Err(e) => {
+            ui.ui_print(&format!("misc bootloader_control unavailable ({:#}), using bootctl", e))?;
+            return crate::func::set_slot(ui, &index.to_string());
+        }
+    };
+    if index >= ctrl.nb_slot() {
+        bail!("Slot {} does not exist (device has {} slots)", slot, ctrl.nb_slot());
+    }
+
+    ctrl.activate(index, current_slot() != Some(index));
+    save(misc, &mut ctrl)?;
+
+    ui.ui_print(&format!("Active slot set to {}", slot_suffix(index)))?;
+    Ok(())
+}
+
+/// Marks `slot` (default: the current slot) as successfully booted.
+pub fn mark_slot_successful(ui: &mut RecoveryUI, slot: Option<&str>) -> Result<()> {
+    let index = match slot {
+        Some(s) => parse_slot(s)?,
+        None => current_slot().context("Could not detect the current slot")?,
+    };
+    let (misc, mut ctrl) = match load() {
+        Ok(c) => c,
+        Err(e) => {
+            ui.ui_print(&format!("misc bootloader_control unavailable ({:#}), using bootctl", e))?;
+            if current_slot() != Some(index) {
+                bail!("bootctl can only mark the current slot as successful");
+            }
+            let out = exec_capture("bootctl", &["mark-boot-successful"])?;
+            if !out.is_empty() { ui.ui_print(&out)?; }
+            return Ok(());
+        }
+    };
+    if index >= ctrl.nb_slot() {
+        bail!("Slot {} does not exist (device has {} slots)", index, ctrl.nb_slot());
+    }
+
+    ctrl.mark_successful(index);
+    save(misc, &mut ctrl)?;
+
+    ui.ui_print(&format!("Slot {} marked successful", slot_suffix(index)))?;
+    Ok(())
+}
+
+pub fn get_slot_info(ui: &mut RecoveryUI) -> Result<()> {
+    let current = current_slot();
+    let ctrl = match load() {
+        Ok((_, c)) => c,
+        Err(e) => {
+            ui.ui_print(&format!("misc bootloader_control unavailable ({:#}), using bootctl", e))?;
+            let slots: usize = exec_capture("bootctl", &["get-number-slots"])?.parse().unwrap_or(0);
+            for i in 0..slots {
+                let idx = i.to_string();
+                let bootable = exec_capture("bootctl", &["is-slot-bootable", &idx])?;
+                let successful = exec_capture("bootctl", &["is-slot-marked-successful", &idx])?;
+                ui.ui_print(&format!(
+                    "Slot {}{}: bootable={} successful={}",
+                    slot_suffix(i),
+                    if current == Some(i) { " (current)" } else { "" },
+                    bootable, successful
+                ))?;
+            }
+            return Ok(());
+        }
+    };
+
+    for i in 0..ctrl.nb_slot() {
+        let s = ctrl.slot(i);
+        ui.ui_print(&format!(
+            "Slot {}{}: priority={} tries={} successful={} verity_corrupted={}",
+            slot_suffix(i),
+            if current == Some(i) { " (current)" } else { "" },
+            s.priority, s.tries_remaining, s.successful_boot, s.verity_corrupted
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two slots: `_a` booted successfully at priority 15, `_b` unbootable
+    /// with its verity flag set.
+    fn control() -> [u8; BOOT_CTRL_SIZE] {
+        let mut raw = [0u8; BOOT_CTRL_SIZE];
+        raw[..4].copy_from_slice(b"_a\0\0");
+        raw[4..8].copy_from_slice(&BOOT_CTRL_MAGIC.to_le_bytes());
+        raw[8] = BOOT_CTRL_VERSION;
+        raw[9] = 2;
+        raw[SLOT_INFO_OFFSET] = 0x80 | (1 << 4) | 15;
+        raw[SLOT_INFO_OFFSET + 3] = 0x01;
+        let crc = crc32fast::hash(&raw[..CRC_OFFSET]);
+        raw[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        raw
+    }
+
+    fn misc_image(name: &str, raw: &[u8; BOOT_CTRL_SIZE]) -> String {
+        let path = std::env::temp_dir().join(format!("rf-bootctrl-{}-{}", std::process::id(), name));
+        let mut image = vec![0xa5u8; 16 * 1024];
+        image[BOOT_CTRL_OFFSET as usize..][..BOOT_CTRL_SIZE].copy_from_slice(raw);
+        std::fs::write(&path, image).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// The `bootloader_control` bytes of `path`, checking nothing around them changed.
+    fn stored(path: &str) -> [u8; BOOT_CTRL_SIZE] {
+        let image = std::fs::read(path).unwrap();
+        let (start, end) = (BOOT_CTRL_OFFSET as usize, BOOT_CTRL_OFFSET as usize + BOOT_CTRL_SIZE);
+        assert!(image[..start].iter().chain(&image[end..]).all(|&b| b == 0xa5));
+        image[start..end].try_into().unwrap()
+    }
+
+    #[test]
+    fn parse_checks_magic_version_and_crc() {
+        let ctrl = BootControl::parse(control()).unwrap();
+        assert_eq!(ctrl.nb_slot(), 2);
+        let a = ctrl.slot(0);
+        assert_eq!((a.priority, a.tries_remaining, a.successful_boot, a.verity_corrupted), (15, 1, true, false));
+        let b = ctrl.slot(1);
+        assert_eq!((b.priority, b.tries_remaining, b.successful_boot, b.verity_corrupted), (0, 0, false, true));
+
+        let mut bad_crc = control();
+        bad_crc[SLOT_INFO_OFFSET + 2] = 7;
+        assert!(BootControl::parse(bad_crc).is_err());
+        let mut bad_magic = control();
+        bad_magic[4] ^= 0xff;
+        assert!(BootControl::parse(bad_magic).is_err());
+        let mut bad_version = control();
+        bad_version[8] = 2;
+        assert!(BootControl::parse(bad_version).is_err());
+    }
+
+    #[test]
+    fn set_slot_rewrites_misc() {
+        let path = misc_image("set", &control());
+        let mut ctrl = read_ctrl(&path).unwrap();
+        ctrl.activate(1, true);
+        save(&path, &mut ctrl).unwrap();
+
+        let raw = stored(&path);
+        assert_eq!(raw[SLOT_INFO_OFFSET], 0x80 | (1 << 4) | 14, "_a is demoted below _b");
+        assert_eq!(raw[SLOT_INFO_OFFSET + 2], (6 << 4) | 15, "_b gets priority 15 and 6 tries");
+        assert_eq!(raw[SLOT_INFO_OFFSET + 3], 0, "switching clears verity_corrupted");
+        assert_eq!(raw[..SLOT_INFO_OFFSET], control()[..SLOT_INFO_OFFSET]);
+        assert_eq!(u32::from_le_bytes(raw[CRC_OFFSET..].try_into().unwrap()), crc32fast::hash(&raw[..CRC_OFFSET]));
+        assert!(read_ctrl(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mark_successful_keeps_one_try() {
+        let mut raw = control();
+        raw[SLOT_INFO_OFFSET + 2] = (6 << 4) | 15;
+        let crc = crc32fast::hash(&raw[..CRC_OFFSET]);
+        raw[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        let path = misc_image("mark", &raw);
+
+        let mut ctrl = read_ctrl(&path).unwrap();
+        ctrl.mark_successful(1);
+        save(&path, &mut ctrl).unwrap();
+
+        let raw = stored(&path);
+        assert_eq!(raw[SLOT_INFO_OFFSET + 2], 0x80 | (1 << 4) | 15);
+        assert_eq!(raw[SLOT_INFO_OFFSET + 3], 0x01, "verity_corrupted is left alone");
+        assert_eq!(raw[SLOT_INFO_OFFSET], 0x80 | (1 << 4) | 15, "_a is untouched");
+        assert!(read_ctrl(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
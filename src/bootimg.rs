@@ -0,0 +1,558 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use sha1::{Digest, Sha1};
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::threaded_writer::ThreadedWriter;
+use crate::func::{get_active_slot_suffix, slot_partition_path};
+
+const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+const VENDOR_BOOT_MAGIC: &[u8; 8] = b"VNDRBOOT";
+const BOOT_V3_PAGE_SIZE: usize = 4096;
+const WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Boot v0-2 split the command line into `cmdline[512]` and `extra_cmdline[1024]`.
+const BOOT_ARGS_OFFSET: usize = 64;
+const BOOT_ARGS_SIZE: usize = 512;
+const BOOT_EXTRA_ARGS_OFFSET: usize = 608;
+const BOOT_EXTRA_ARGS_SIZE: usize = 1024;
+const BOOT_ID_OFFSET: usize = 576;
+const BOOT_RECOVERY_DTBO_OFFSET: usize = 1636;
+const BOOT_V3_ARGS_OFFSET: usize = 44;
+const BOOT_V3_ARGS_SIZE: usize = 1536;
+const VENDOR_ARGS_OFFSET: usize = 28;
+const VENDOR_ARGS_SIZE: usize = 2048;
+const VENDOR_TABLE_ENTRY_NUM_OFFSET: usize = 2116;
+const VENDOR_TABLE_ENTRY_SIZE_OFFSET: usize = 2120;
+const VENDOR_RAMDISK_NAME_OFFSET: usize = 12;
+const VENDOR_RAMDISK_NAME_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Boot,
+    VendorBoot,
+}
+
+/// One payload after the header, with the header offset of its size field.
+pub struct Section {
+    pub name: &'static str,
+    size_offset: usize,
+    pub data: Vec<u8>,
+}
+
+/// An Android boot image (header v0-4) or vendor_boot image (v3/v4). The raw
+/// header is kept as-is, so fields we don't touch survive a repack.
+pub struct BootImage {
+    pub kind: Kind,
+    pub version: u32,
+    page_size: usize,
+    header: Vec<u8>,
+    pub sections: Vec<Section>,
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("boot image header is truncated")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align(value: usize, page: usize) -> usize {
+    value.div_ceil(page) * page
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// Header length and `(name, size field offset)` of each section, in image order.
+fn layout(kind: Kind, version: u32) -> Result<(usize, Vec<(&'static str, usize)>)> {
+    let layout = match (kind, version) {
+        (Kind::Boot, 0) => (1632, vec![("kernel", 8), ("ramdisk", 16), ("second", 24)]),
+        (Kind::Boot, 1) => (1648, vec![("kernel", 8), ("ramdisk", 16), ("second", 24), ("recovery_dtbo", 1632)]),
+        (Kind::Boot, 2) => (1660, vec![("kernel", 8), ("ramdisk", 16), ("second", 24), ("recovery_dtbo", 1632), ("dtb", 1648)]),
+        (Kind::Boot, 3) => (1580, vec![("kernel", 8), ("ramdisk", 12)]),
+        (Kind::Boot, 4) => (1584, vec![("kernel", 8), ("ramdisk", 12), ("signature", 1580)]),
+        (Kind::VendorBoot, 3) => (2112, vec![("ramdisk", 24), ("dtb", 2100)]),
+        (Kind::VendorBoot, 4) => (2128, vec![("ramdisk", 24), ("dtb", 2100), ("ramdisk_table", 2112), ("bootconfig", 2124)]),
+        _ => bail!("unsupported boot image header version {}", version),
+    };
+    Ok(layout)
+}
+
+impl BootImage {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let magic = data.get(..8).context("boot image is truncated")?;
+        let (kind, version, page_size) = if magic == BOOT_MAGIC {
+            let version = le_u32(data, 40)?;
+            let page_size = if version >= 3 { BOOT_V3_PAGE_SIZE } else { le_u32(data, 36)? as usize };
+            (Kind::Boot, version, page_size)
+        } else if magic == VENDOR_BOOT_MAGIC {
+            (Kind::VendorBoot, le_u32(data, 8)?, le_u32(data, 12)? as usize)
+        } else {
+            bail!("not a boot or vendor_boot image (bad magic)");
+        };
+        if page_size == 0 || !page_size.is_power_of_two() {
+            bail!("invalid page size {}", page_size);
+        }
+
+        let (header_len, fields) = layout(kind, version)?;
+        let header = data.get(..header_len).context("boot image header is truncated")?.to_vec();
+        let header_pages = match kind {
+            Kind::Boot => align(header_len, page_size),
+            Kind::VendorBoot => align(le_u32(data, 2096)? as usize, page_size),
+        };
+
+        let mut pos = header_pages;
+        let mut sections = Vec::with_capacity(fields.len());
+        for (name, size_offset) in fields {
+            let size = le_u32(data, size_offset)? as usize;
+            let section = data
+                .get(pos..pos + size)
+                .context(format!("boot image is truncated in {}", name))?;
+            sections.push(Section { name, size_offset, data: section.to_vec() });
+            pos += align(size, page_size);
+        }
+
+        Ok(BootImage { kind, version, page_size, header, sections })
+    }
+
+    /// Reads the image at the start of `path`. Padding and anything after the
+    /// last section (e.g. an AVB footer) is not kept.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut file = File::open(path).context(format!("Failed to open {}", path))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).context(format!("Failed to read {}", path))?;
+        BootImage::parse(&data).context(format!("Failed to parse {}", path))
+    }
+
+    pub fn section(&self, name: &str) -> Option<&[u8]> {
+        self.sections.iter().find(|s| s.name == name).map(|s| s.data.as_slice())
+    }
+
+    /// Replaces a section. On vendor_boot v4, `ramdisk:<name>` replaces one
+    /// fragment of the vendor ramdisk table.
+    pub fn replace(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        if let Some(fragment) = name.strip_prefix("ramdisk:") {
+            return self.replace_fragment(fragment, data);
+        }
+        if self.kind == Kind::VendorBoot && self.version >= 4 && name == "ramdisk" {
+            match self.fragment_count()? {
+                0 | 1 => {},
+                _ => bail!("vendor_boot has several ramdisk fragments, use ramdisk:<name>"),
+            }
+        }
+
+        let describe = self.describe();
+        let section = self
+            .sections
+            .iter_mut()
+            .find(|s| s.name == name)
+            .context(format!("{} has no {} section", describe, name))?;
+        section.data = data;
+
+        if self.kind == Kind::VendorBoot && self.version >= 4 && name == "ramdisk" && self.fragment_count()? == 1 {
+            let size = self.section("ramdisk").map_or(0, |d| d.len()) as u32;
+            let table = self.section_mut("ramdisk_table")?;
+            put_u32(table, 0, size);
+            put_u32(table, 4, 0);
+        }
+        Ok(())
+    }
+
+    fn section_mut(&mut self, name: &str) -> Result<&mut Vec<u8>> {
+        self.sections
+            .iter_mut()
+            .find(|s| s.name == name)
+            .map(|s| &mut s.data)
+            .context(format!("no {} section", name))
+    }
+
+    fn fragment_count(&self) -> Result<usize> {
+        Ok(le_u32(&self.header, VENDOR_TABLE_ENTRY_NUM_OFFSET)? as usize)
+    }
+
+    /// Vendor ramdisk table entries as `(name, offset, size)`.
+    pub fn fragments(&self) -> Result<Vec<(String, usize, usize)>> {
+        if self.kind != Kind::VendorBoot || self.version < 4 {
+            return Ok(Vec::new());
+        }
+        let entry_size = le_u32(&self.header, VENDOR_TABLE_ENTRY_SIZE_OFFSET)? as usize;
+        let table = self.section("ramdisk_table").unwrap_or_default();
+        let mut fragments = Vec::new();
+        for i in 0..self.fragment_count()? {
+            let entry = table.get(i * entry_size..(i + 1) * entry_size).context("vendor ramdisk table is truncated")?;
+            let name = c_string(&entry[VENDOR_RAMDISK_NAME_OFFSET..VENDOR_RAMDISK_NAME_OFFSET + VENDOR_RAMDISK_NAME_SIZE]);
+            fragments.push((name, le_u32(entry, 4)? as usize, le_u32(entry, 0)? as usize));
+        }
+        Ok(fragments)
+    }
+
+    /// The whole ramdisk, or one vendor_boot v4 fragment for `ramdisk:<name>`.
+    pub fn ramdisk(&self, name: &str) -> Result<&[u8]> {
+        let ramdisk = self.section("ramdisk").context(format!("{} has no ramdisk", self.describe()))?;
+        let Some(fragment) = name.strip_prefix("ramdisk:") else { return Ok(ramdisk); };
+        let (_, offset, size) = self
+            .fragments()?
+            .into_iter()
+            .find(|(n, _, _)| n == fragment)
+            .context(format!("no vendor ramdisk fragment named {}", fragment))?;
+        ramdisk.get(offset..offset + size).context("vendor ramdisk table is out of range")
+    }
+
+    fn replace_fragment(&mut self, fragment: &str, data: Vec<u8>) -> Result<()> {
+        let fragments = self.fragments()?;
+        let index = fragments
+            .iter()
+            .position(|(n, _, _)| n == fragment)
+            .context(format!("no vendor ramdisk fragment named {}", fragment))?;
+
+        let old = self.section("ramdisk").unwrap_or_default().to_vec();
+        let mut ramdisk = Vec::with_capacity(old.len() + data.len());
+        let mut placed = Vec::with_capacity(fragments.len());
+        for (i, (_, offset, size)) in fragments.iter().enumerate() {
+            let start = ramdisk.len();
+            if i == index {
+                ramdisk.extend_from_slice(&data);
+            } else {
+                ramdisk.extend_from_slice(old.get(*offset..offset + size).context("vendor ramdisk table is out of range")?);
+            }
+            placed.push((start, ramdisk.len() - start));
+        }
+
+        let entry_size = le_u32(&self.header, VENDOR_TABLE_ENTRY_SIZE_OFFSET)? as usize;
+        let table = self.section_mut("ramdisk_table")?;
+        for (i, (offset, size)) in placed.into_iter().enumerate() {
+            put_u32(table, i * entry_size, size as u32);
+            put_u32(table, i * entry_size + 4, offset as u32);
+        }
+        *self.section_mut("ramdisk")? = ramdisk;
+        Ok(())
+    }
+
+    pub fn cmdline(&self) -> String {
+        match (self.kind, self.version) {
+            (Kind::Boot, 0..=2) => {
+                let main = c_string(&self.header[BOOT_ARGS_OFFSET..BOOT_ARGS_OFFSET + BOOT_ARGS_SIZE]);
+                let extra = c_string(&self.header[BOOT_EXTRA_ARGS_OFFSET..BOOT_EXTRA_ARGS_OFFSET + BOOT_EXTRA_ARGS_SIZE]);
+                main + &extra
+            },
+            (Kind::Boot, _) => c_string(&self.header[BOOT_V3_ARGS_OFFSET..BOOT_V3_ARGS_OFFSET + BOOT_V3_ARGS_SIZE]),
+            (Kind::VendorBoot, _) => c_string(&self.header[VENDOR_ARGS_OFFSET..VENDOR_ARGS_OFFSET + VENDOR_ARGS_SIZE]),
+        }
+    }
+
+    pub fn set_cmdline(&mut self, cmdline: &str) -> Result<()> {
+        let bytes = cmdline.as_bytes();
+        let mut fill = |offset: usize, size: usize, value: &[u8]| {
+            self.header[offset..offset + size].fill(0);
+            self.header[offset..offset + value.len()].copy_from_slice(value);
+        };
+        match (self.kind, self.version) {
+            (Kind::Boot, 0..=2) => {
+                if bytes.len() > BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE - 2 {
+                    bail!("cmdline is {} bytes, the header fits {}", bytes.len(), BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE - 2);
+                }
+                let split = bytes.len().min(BOOT_ARGS_SIZE - 1);
+                fill(BOOT_ARGS_OFFSET, BOOT_ARGS_SIZE, &bytes[..split]);
+                fill(BOOT_EXTRA_ARGS_OFFSET, BOOT_EXTRA_ARGS_SIZE, &bytes[split..]);
+            },
+            (Kind::Boot, _) => {
+                if bytes.len() >= BOOT_V3_ARGS_SIZE {
+                    bail!("cmdline is {} bytes, the header fits {}", bytes.len(), BOOT_V3_ARGS_SIZE - 1);
+                }
+                fill(BOOT_V3_ARGS_OFFSET, BOOT_V3_ARGS_SIZE, bytes);
+            },
+            (Kind::VendorBoot, _) => {
+                if bytes.len() >= VENDOR_ARGS_SIZE {
+                    bail!("cmdline is {} bytes, the header fits {}", bytes.len(), VENDOR_ARGS_SIZE - 1);
+                }
+                fill(VENDOR_ARGS_OFFSET, VENDOR_ARGS_SIZE, bytes);
+            },
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        match self.kind {
+            Kind::Boot => format!("boot image v{}", self.version),
+            Kind::VendorBoot => format!("vendor_boot image v{}", self.version),
+        }
+    }
+
+    /// Serializes the image, updating section sizes, the v1/v2 recovery dtbo
+    /// offset and the v0-v2 SHA-1 id.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        for section in &self.sections {
+            put_u32(&mut header, section.size_offset, section.data.len() as u32);
+        }
+
+        let header_pages = match self.kind {
+            Kind::Boot => align(header.len(), self.page_size),
+            Kind::VendorBoot => align(le_u32(&header, 2096).unwrap_or(header.len() as u32) as usize, self.page_size),
+        };
+
+        if self.kind == Kind::Boot && self.version <= 2 {
+            if self.version >= 1 {
+                let mut offset = header_pages;
+                for section in self.sections.iter().take_while(|s| s.name != "recovery_dtbo") {
+                    offset += align(section.data.len(), self.page_size);
+                }
+                let recovery_dtbo = self.section("recovery_dtbo").map_or(0, |d| d.len());
+                let offset = if recovery_dtbo > 0 { offset as u64 } else { 0 };
+                header[BOOT_RECOVERY_DTBO_OFFSET..BOOT_RECOVERY_DTBO_OFFSET + 8].copy_from_slice(&offset.to_le_bytes());
+            }
+
+            let mut sha = Sha1::new();
+            for section in &self.sections {
+                sha.update(&section.data);
+                sha.update((section.data.len() as u32).to_le_bytes());
+            }
+            header[BOOT_ID_OFFSET..BOOT_ID_OFFSET + 32].fill(0);
+            header[BOOT_ID_OFFSET..BOOT_ID_OFFSET + 20].copy_from_slice(&sha.finalize());
+        }
+
+        let mut out = header;
+        out.resize(header_pages, 0);
+        for section in &self.sections {
+            out.extend_from_slice(&section.data);
+            out.resize(align(out.len(), self.page_size), 0);
+        }
+        out
+    }
+}
+
+/// Reads a component from the zip, or from the filesystem for absolute paths.
+pub fn read_source(archive: &mut ZipArchive<File>, source: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if source.starts_with('/') {
+        File::open(source)
+            .context(format!("Failed to open {}", source))?
+            .read_to_end(&mut data)?;
+    } else {
+        archive
+            .by_name(source)
+            .context(format!("{} not found in ZIP", source))?
+            .read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// `boot` -> `/dev/block/by-name/boot<current slot>`; paths are used as-is.
+pub fn resolve_image_path(target: &str) -> String {
+    if target.contains('/') {
+        target.to_string()
+    } else {
+        slot_partition_path(target, &get_active_slot_suffix().unwrap_or_default())
+    }
+}
+
+/// Writes a repacked image to a partition or file.
+pub fn write_image(ui: &mut RecoveryUI, image: &BootImage, dest: &str) -> Result<()> {
+    write_data(ui, &image.describe(), &image.to_bytes(), dest)
+}
+
+/// Writes `data` to a partition or file the same way `package_extract_file`
+/// does, after checking it fits.
+pub fn write_data(ui: &mut RecoveryUI, label: &str, data: &[u8], dest: &str) -> Result<()> {
+    crate::blockdev::ensure_fits(ui, label, data.len() as u64, dest)?;
+    if let Some(parent) = Path::new(dest).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = ThreadedWriter::new(dest.to_string(), WRITE_BUFFER_SIZE);
+    writer.write_all(data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Adds or replaces `key=value` (or a bare flag) in a kernel command line.
+fn cmdline_add(cmdline: &str, arg: &str) -> String {
+    let key = arg.split('=').next().unwrap_or(arg);
+    let mut args: Vec<&str> = cmdline.split_whitespace().filter(|a| a.split('=').next() != Some(key)).collect();
+    args.push(arg);
+    args.join(" ")
+}
+
+fn cmdline_remove(cmdline: &str, key: &str) -> String {
+    cmdline
+        .split_whitespace()
+        .filter(|a| a.split('=').next() != Some(key))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `boot_image_unpack <image> <dir>`: writes every non-empty section and the
+/// cmdline to `<dir>`.
+pub fn boot_image_unpack(ui: &mut RecoveryUI, target: &str, dir: &str) -> Result<()> {
+    let path = resolve_image_path(target);
+    let image = BootImage::load(&path)?;
+    ui.ui_print(&format!("Unpacking {} ({}) to {}...", path, image.describe(), dir))?;
+
+    fs::create_dir_all(dir)?;
+    for section in image.sections.iter().filter(|s| !s.data.is_empty()) {
+        fs::write(Path::new(dir).join(section.name), &section.data)?;
+        ui.ui_print(&format!("  {}: {} bytes", section.name, section.data.len()))?;
+    }
+    fs::write(Path::new(dir).join("cmdline"), image.cmdline())?;
+    Ok(())
+}
+
+/// `boot_image_repack <image> [--kernel=src] [--ramdisk=src] [--dtb=src] ...`:
+/// replaces sections and edits the cmdline, then writes the image back (or
+/// to `--out=`).
+pub fn boot_image_repack(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, args: &[String]) -> Result<()> {
+    let target = args.first().context("boot_image_repack requires <image>")?;
+    let path = resolve_image_path(target);
+    let mut image = BootImage::load(&path)?;
+    let mut dest = path.clone();
+
+    ui.ui_print(&format!("Repacking {} ({})...", path, image.describe()))?;
+    for arg in &args[1..] {
+        let (option, value) = arg
+            .strip_prefix("--")
+            .and_then(|a| a.split_once('='))
+            .context(format!("Invalid boot_image_repack option: {}", arg))?;
+        match option {
+            "cmdline" => image.set_cmdline(value)?,
+            "cmdline-add" => image.set_cmdline(&cmdline_add(&image.cmdline(), value))?,
+            "cmdline-remove" => image.set_cmdline(&cmdline_remove(&image.cmdline(), value))?,
+            "out" => dest = resolve_image_path(value),
+            section => {
+                let data = read_source(archive, value)?;
+                ui.ui_print(&format!("  {}: {} bytes from {}", section, data.len(), value))?;
+                image.replace(section, data)?;
+            },
+        }
+    }
+
+    write_image(ui, &image, &dest)?;
+    ui.ui_print(&format!("  Written to {}", dest))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad(out: &mut Vec<u8>, page: usize) {
+        out.resize(align(out.len(), page), 0);
+    }
+
+    /// Boot v2 with 2048-byte pages: kernel, ramdisk and dtb, no second or recovery dtbo.
+    fn boot_v2(kernel: &[u8], ramdisk: &[u8], dtb: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; 1660];
+        out[..8].copy_from_slice(BOOT_MAGIC);
+        for (offset, value) in [(8, kernel.len()), (16, ramdisk.len()), (36, 2048), (40, 2), (1648, dtb.len())] {
+            put_u32(&mut out, offset, value as u32);
+        }
+        for section in [&[][..], kernel, ramdisk, dtb] {
+            out.extend_from_slice(section);
+            pad(&mut out, 2048);
+        }
+        out
+    }
+
+    /// vendor_boot v4 with 4096-byte pages and one table entry per fragment.
+    fn vendor_boot_v4(fragments: &[(&str, &[u8])]) -> Vec<u8> {
+        const ENTRY_SIZE: usize = 108;
+        let mut ramdisk = Vec::new();
+        let mut table = vec![0; fragments.len() * ENTRY_SIZE];
+        for (i, (name, data)) in fragments.iter().enumerate() {
+            let entry = &mut table[i * ENTRY_SIZE..];
+            put_u32(entry, 0, data.len() as u32);
+            put_u32(entry, 4, ramdisk.len() as u32);
+            entry[VENDOR_RAMDISK_NAME_OFFSET..VENDOR_RAMDISK_NAME_OFFSET + name.len()].copy_from_slice(name.as_bytes());
+            ramdisk.extend_from_slice(data);
+        }
+
+        let mut out = vec![0; 2128];
+        out[..8].copy_from_slice(VENDOR_BOOT_MAGIC);
+        for (offset, value) in [
+            (8, 4), (12, 4096), (24, ramdisk.len()), (2096, 2128), (2112, table.len()),
+            (VENDOR_TABLE_ENTRY_NUM_OFFSET, fragments.len()), (VENDOR_TABLE_ENTRY_SIZE_OFFSET, ENTRY_SIZE),
+        ] {
+            put_u32(&mut out, offset, value as u32);
+        }
+        for section in [&[][..], &ramdisk, &table] {
+            out.extend_from_slice(section);
+            pad(&mut out, 4096);
+        }
+        out
+    }
+
+    fn fragment<'a>(image: &'a BootImage, name: &str) -> &'a [u8] {
+        let (_, offset, size) = image.fragments().unwrap().into_iter().find(|(n, _, _)| n == name).unwrap();
+        &image.section("ramdisk").unwrap()[offset..offset + size]
+    }
+
+    #[test]
+    fn boot_v2_sections_and_offsets() {
+        let data = boot_v2(b"kernel", &[7; 3000], b"dtb");
+        let mut image = BootImage::parse(&data).unwrap();
+        assert_eq!(image.describe(), "boot image v2");
+        assert_eq!(image.section("kernel").unwrap(), b"kernel");
+        assert_eq!(image.section("ramdisk").unwrap(), &[7; 3000]);
+        assert_eq!(image.section("dtb").unwrap(), b"dtb");
+
+        // Same layout, only the id is filled in.
+        let bytes = image.to_bytes();
+        assert_eq!(bytes.len(), data.len());
+        assert_eq!(bytes[..BOOT_ID_OFFSET], data[..BOOT_ID_OFFSET]);
+        assert_eq!(bytes[BOOT_ID_OFFSET + 32..], data[BOOT_ID_OFFSET + 32..]);
+        assert_eq!(le_u32(&bytes, BOOT_RECOVERY_DTBO_OFFSET).unwrap(), 0);
+
+        // header + kernel + 2 ramdisk pages, second is empty.
+        image.replace("recovery_dtbo", b"dtbo".to_vec()).unwrap();
+        let bytes = image.to_bytes();
+        assert_eq!(le_u32(&bytes, BOOT_RECOVERY_DTBO_OFFSET).unwrap(), 4 * 2048);
+        assert_eq!(&bytes[4 * 2048..4 * 2048 + 4], b"dtbo");
+        assert_eq!(BootImage::parse(&bytes).unwrap().section("dtb").unwrap(), b"dtb");
+    }
+
+    #[test]
+    fn boot_v2_cmdline_spills_into_extra_args() {
+        let mut image = BootImage::parse(&boot_v2(b"k", b"r", b"")).unwrap();
+        let cmdline = format!("console=ttyMSM0 {}", "x".repeat(600));
+        image.set_cmdline(&cmdline).unwrap();
+        assert_eq!(image.cmdline(), cmdline);
+
+        let bytes = image.to_bytes();
+        assert_eq!(bytes[BOOT_ARGS_OFFSET + BOOT_ARGS_SIZE - 1], 0);
+        assert_eq!(bytes[BOOT_EXTRA_ARGS_OFFSET], b'x');
+        assert!(image.set_cmdline(&"x".repeat(BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE)).is_err());
+    }
+
+    #[test]
+    fn vendor_boot_v4_fragments() {
+        let data = vendor_boot_v4(&[("first", b"aaaa"), ("second", b"bb")]);
+        let mut image = BootImage::parse(&data).unwrap();
+        assert_eq!(image.to_bytes(), data);
+        assert_eq!(fragment(&image, "second"), b"bb");
+        assert!(image.replace("ramdisk", Vec::new()).is_err());
+
+        image.replace("ramdisk:first", b"cccccc".to_vec()).unwrap();
+        let image = BootImage::parse(&image.to_bytes()).unwrap();
+        assert_eq!(
+            image.fragments().unwrap(),
+            [("first".to_string(), 0, 6), ("second".to_string(), 6, 2)]
+        );
+        assert_eq!(fragment(&image, "first"), b"cccccc");
+        assert_eq!(fragment(&image, "second"), b"bb");
+    }
+
+    #[test]
+    fn rejects_unknown_images() {
+        assert!(BootImage::parse(b"NOTBOOT!").is_err());
+        let mut data = boot_v2(b"k", b"r", b"");
+        put_u32(&mut data, 40, 9);
+        assert!(BootImage::parse(&data).is_err());
+        assert!(BootImage::parse(&boot_v2(b"k", b"r", b"")[..2048 + 1]).is_err());
+    }
+}
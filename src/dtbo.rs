@@ -0,0 +1,301 @@
+use std::fs::File;
+use anyhow::{Context, Result, bail};
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::bootimg::{read_source, resolve_image_path, write_data};
+
+const DT_TABLE_MAGIC: u32 = 0xd7b7ab1e;
+const DT_TABLE_HEADER_SIZE: usize = 32;
+const DT_TABLE_ENTRY_SIZE: usize = 32;
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+fn be_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("dtbo image is truncated")?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// One `dt_table_entry`: a device tree overlay with its id/rev and the
+/// vendor-defined `custom` words (v1 keeps compression flags in the first).
+pub struct DtEntry {
+    pub id: u32,
+    pub rev: u32,
+    custom: [u8; 16],
+    pub data: Vec<u8>,
+}
+
+/// An Android DTBO image (`dt_table_header` followed by entries and blobs).
+pub struct Dtbo {
+    page_size: u32,
+    version: u32,
+    pub entries: Vec<DtEntry>,
+}
+
+impl Dtbo {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if be_u32(data, 0)? != DT_TABLE_MAGIC {
+            bail!("not a dtbo image (bad magic)");
+        }
+        let entry_size = be_u32(data, 12)? as usize;
+        let entry_count = be_u32(data, 16)? as usize;
+        let entries_offset = be_u32(data, 20)? as usize;
+        if entry_size < DT_TABLE_ENTRY_SIZE {
+            bail!("dt_table_entry size {} is too small", entry_size);
+        }
+        let table_end = entry_count
+            .checked_mul(entry_size)
+            .and_then(|len| len.checked_add(entries_offset))
+            .filter(|&end| end <= data.len());
+        if table_end.is_none() {
+            bail!("dtbo entry table ({} entries at {:#x}) is out of range", entry_count, entries_offset);
+        }
+
+        let mut entries = Vec::with_capacity(entry_count);
+        for i in 0..entry_count {
+            let at = entries_offset + i * entry_size;
+            let size = be_u32(data, at)? as usize;
+            let offset = be_u32(data, at + 4)? as usize;
+            let blob = data.get(offset..offset.saturating_add(size)).context(format!("dtbo entry {} is out of range", i))?;
+            entries.push(DtEntry {
+                id: be_u32(data, at + 8)?,
+                rev: be_u32(data, at + 12)?,
+                custom: data
+                    .get(at + 16..at + 32)
+                    .context(format!("dtbo entry {} is truncated", i))?
+                    .try_into()
+                    .unwrap(),
+                data: blob.to_vec(),
+            });
+        }
+        Ok(Dtbo { page_size: be_u32(data, 24)?, version: be_u32(data, 28)?, entries })
+    }
+
+    /// Puts the raw DTB `data` in place of every entry matching `selector`
+    /// and returns how many were replaced. On v1 tables the `flags` word is
+    /// cleared, so the entries aren't marked compressed anymore.
+    fn replace(&mut self, selector: (u32, Option<u32>), data: &[u8]) -> Result<usize> {
+        if be_u32(data, 0)? != FDT_MAGIC {
+            bail!("not an uncompressed device tree blob");
+        }
+        let mut replaced = 0;
+        for entry in self.entries.iter_mut().filter(|e| matches(e, selector)) {
+            entry.data = data.to_vec();
+            if self.version >= 1 {
+                entry.custom[..4].fill(0);
+            }
+            replaced += 1;
+        }
+        Ok(replaced)
+    }
+
+    /// Rebuilds the image like mkdtboimg: header, entry table, then the
+    /// blobs, with identical blobs stored once.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let table_end = DT_TABLE_HEADER_SIZE + self.entries.len() * DT_TABLE_ENTRY_SIZE;
+        let mut blobs: Vec<u8> = Vec::new();
+        let mut placed: Vec<(usize, usize)> = Vec::with_capacity(self.entries.len());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let offset = match self.entries[..i].iter().position(|e| e.data == entry.data) {
+                Some(prev) => placed[prev].0,
+                None => {
+                    let offset = table_end + blobs.len();
+                    blobs.extend_from_slice(&entry.data);
+                    offset
+                },
+            };
+            placed.push((offset, entry.data.len()));
+        }
+
+        let mut out = Vec::with_capacity(table_end + blobs.len());
+        for value in [
+            DT_TABLE_MAGIC,
+            (table_end + blobs.len()) as u32,
+            DT_TABLE_HEADER_SIZE as u32,
+            DT_TABLE_ENTRY_SIZE as u32,
+            self.entries.len() as u32,
+            DT_TABLE_HEADER_SIZE as u32,
+            self.page_size,
+            self.version,
+        ] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        for (entry, (offset, size)) in self.entries.iter().zip(placed) {
+            for value in [size as u32, offset as u32, entry.id, entry.rev] {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            out.extend_from_slice(&entry.custom);
+        }
+        out.extend_from_slice(&blobs);
+        out
+    }
+}
+
+/// Parses `<id>` or `<id>:<rev>` (decimal or `0x` hex) into a matcher.
+fn parse_selector(selector: &str) -> Result<(u32, Option<u32>)> {
+    let number = |s: &str| -> Result<u32> {
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .context(format!("Invalid dtbo id/rev: {}", selector))
+    };
+    match selector.split_once(':') {
+        Some((id, rev)) => Ok((number(id)?, Some(number(rev)?))),
+        None => Ok((number(selector)?, None)),
+    }
+}
+
+fn matches(entry: &DtEntry, (id, rev): (u32, Option<u32>)) -> bool {
+    entry.id == id && rev.is_none_or(|r| entry.rev == r)
+}
+
+/// Loads a dtbo from a ZIP entry, a file, or a partition name (`dtbo`
+/// resolves to the current slot).
+fn load(archive: &mut ZipArchive<File>, source: &str) -> Result<Dtbo> {
+    let path = if archive.by_name(source).is_ok() { source.to_string() } else { resolve_image_path(source) };
+    let data = read_source(archive, &path)?;
+    Dtbo::parse(&data).context(format!("Failed to parse {}", path))
+}
+
+/// `dtbo_list <dtbo>`: prints every entry with its id and rev.
+pub fn dtbo_list(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, source: &str) -> Result<()> {
+    let dtbo = load(archive, source)?;
+    ui.ui_print(&format!("{}: {} entries", source, dtbo.entries.len()))?;
+    for (i, entry) in dtbo.entries.iter().enumerate() {
+        ui.ui_print(&format!("  [{}] id=0x{:x} rev=0x{:x} size={}", i, entry.id, entry.rev, entry.data.len()))?;
+    }
+    Ok(())
+}
+
+/// `dtbo_build <src> <dest> [--select=<id[:rev]>]... [--replace=<id[:rev]>=<dtb>]...`:
+/// keeps only the selected entries (all if none are given), swaps in
+/// replacement overlays, and writes the rebuilt table to `<dest>`.
+pub fn dtbo_build(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, args: &[String]) -> Result<()> {
+    if args.len() < 2 { bail!("dtbo_build requires <src> <dest>"); }
+    let mut dtbo = load(archive, &args[0])?;
+    let dest = resolve_image_path(&args[1]);
+    ui.ui_print(&format!("Building dtbo from {} ({} entries)...", args[0], dtbo.entries.len()))?;
+
+    let mut selectors = Vec::new();
+    let mut replacements = Vec::new();
+    for arg in &args[2..] {
+        if let Some(selector) = arg.strip_prefix("--select=") {
+            selectors.push(parse_selector(selector)?);
+        } else if let Some(replace) = arg.strip_prefix("--replace=") {
+            let (selector, source) = replace
+                .split_once('=')
+                .context(format!("Invalid --replace, expected <id[:rev]>=<dtb>: {}", replace))?;
+            replacements.push((parse_selector(selector)?, source.to_string()));
+        } else {
+            bail!("Invalid dtbo_build option: {}", arg);
+        }
+    }
+
+    if !selectors.is_empty() {
+        dtbo.entries.retain(|e| selectors.iter().any(|&s| matches(e, s)));
+        if dtbo.entries.is_empty() {
+            let msg = format!("No entry in {} matches the selected id/rev. Aborting...", args[0]);
+            let _ = ui.ui_print(&msg);
+            bail!("{}", msg);
+        }
+    }
+
+    for (selector, source) in replacements {
+        let data = read_source(archive, &source)?;
+        let replaced = dtbo.replace(selector, &data).context(format!("Can't replace with {}", source))?;
+        if replaced == 0 {
+            bail!("No entry with id 0x{:x} to replace with {}", selector.0, source);
+        }
+        ui.ui_print(&format!("  Replaced {} entries with {}", replaced, source))?;
+    }
+
+    for entry in &dtbo.entries {
+        ui.ui_print(&format!("  id=0x{:x} rev=0x{:x} size={}", entry.id, entry.rev, entry.data.len()))?;
+    }
+    write_data(ui, "dtbo", &dtbo.to_bytes(), &dest)?;
+    ui.ui_print(&format!("  Written to {}", dest))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fdt(tag: u8) -> Vec<u8> {
+        let mut blob = FDT_MAGIC.to_be_bytes().to_vec();
+        blob.extend_from_slice(&[tag; 12]);
+        blob
+    }
+
+    fn entry(id: u32, rev: u32, data: Vec<u8>) -> DtEntry {
+        DtEntry { id, rev, custom: [0; 16], data }
+    }
+
+    #[test]
+    fn round_trip_dedups_identical_blobs() {
+        let dtbo = Dtbo {
+            page_size: 4096,
+            version: 0,
+            entries: vec![entry(1, 0, fdt(1)), entry(2, 1, fdt(2)), entry(3, 0, fdt(1))],
+        };
+        let bytes = dtbo.to_bytes();
+        assert_eq!(bytes.len(), DT_TABLE_HEADER_SIZE + 3 * DT_TABLE_ENTRY_SIZE + 2 * 16);
+
+        let parsed = Dtbo::parse(&bytes).unwrap();
+        assert_eq!(parsed.page_size, 4096);
+        let ids: Vec<_> = parsed.entries.iter().map(|e| (e.id, e.rev, e.data.clone())).collect();
+        assert_eq!(ids, vec![(1, 0, fdt(1)), (2, 1, fdt(2)), (3, 0, fdt(1))]);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn truncated_entry_table_is_an_error() {
+        // Blob first, entry table last, cut in the middle of the entry.
+        let blob = fdt(1);
+        let table_at = DT_TABLE_HEADER_SIZE + blob.len();
+        let mut image = Vec::new();
+        for value in [DT_TABLE_MAGIC, 0, 32, 32, 1, table_at as u32, 4096, 0] {
+            image.extend_from_slice(&value.to_be_bytes());
+        }
+        image.extend_from_slice(&blob);
+        for value in [blob.len() as u32, DT_TABLE_HEADER_SIZE as u32, 7, 0] {
+            image.extend_from_slice(&value.to_be_bytes());
+        }
+        image.extend_from_slice(&[0; 16]);
+        assert_eq!(Dtbo::parse(&image).unwrap().entries[0].id, 7);
+
+        for len in [table_at + 8, table_at + 20] {
+            assert!(Dtbo::parse(&image[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn replace_clears_v1_compression_flags() {
+        let mut compressed = entry(1, 0, vec![0x78, 0x9c, 0, 0]);
+        compressed.custom[..4].copy_from_slice(&1u32.to_be_bytes());
+        compressed.custom[4..8].copy_from_slice(&7u32.to_be_bytes());
+        let mut dtbo = Dtbo { page_size: 4096, version: 1, entries: vec![compressed, entry(2, 0, fdt(2))] };
+
+        assert!(dtbo.replace((1, None), &[0x78, 0x9c, 0, 0]).is_err());
+        assert_eq!(dtbo.replace((1, None), &fdt(9)).unwrap(), 1);
+        let parsed = Dtbo::parse(&dtbo.to_bytes()).unwrap();
+        assert_eq!(parsed.entries[0].data, fdt(9));
+        assert_eq!(parsed.entries[0].custom[..8], [0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(dtbo.replace((5, None), &fdt(9)).unwrap(), 0);
+
+        // v0 custom words are vendor data and stay as they are.
+        let mut v0 = Dtbo { page_size: 4096, version: 0, entries: vec![entry(1, 0, fdt(1))] };
+        v0.entries[0].custom[0] = 0xaa;
+        v0.replace((1, None), &fdt(3)).unwrap();
+        assert_eq!(v0.entries[0].custom[0], 0xaa);
+    }
+
+    #[test]
+    fn selectors() {
+        assert_eq!(parse_selector("0x2:1").unwrap(), (2, Some(1)));
+        assert_eq!(parse_selector("17").unwrap(), (17, None));
+        assert!(parse_selector("x").is_err());
+        assert!(matches(&entry(2, 5, Vec::new()), (2, None)));
+        assert!(!matches(&entry(2, 5, Vec::new()), (2, Some(1))));
+    }
+}
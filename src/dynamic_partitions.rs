@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::Path;
 use std::process::{Command, Stdio};
 use anyhow::{Context, Result, bail};
@@ -10,6 +10,14 @@ use std::os::unix::fs::PermissionsExt;
 use crate::recovery::RecoveryUI;
 use crate::func::{package_extract_file, exec_binary, get_active_slot_suffix}; 
 
+const SUPER_DEVICE: &str = "/dev/block/by-name/super";
+const LP_PARTITION_RESERVED_BYTES: u64 = 4096;
+const LP_METADATA_GEOMETRY_SIZE: u64 = 4096;
+const LP_BACKUP_PATHS: [&str; 2] = [
+    "/tmp/lp_metadata_backup.img",
+    "/sdcard/RapidFlasher/lp_metadata_backup.img",
+];
+
 #[derive(Debug, Default)]
 struct SuperInfo {
     metadata_size: u64,
@@ -46,13 +54,40 @@ pub fn update_dynamic_partitions(
         default_ops
     };
 
-    if !ops.remove_all_groups && ops.groups.is_empty() {
-        incremental_update(ui, &ops)?;
+    let info = parse_lpdump();
+    let backup = match &info {
+        Ok(info) => backup_metadata(ui, info),
+        Err(e) => Err(anyhow::anyhow!("lpdump failed: {:#}", e)),
+    };
+    let backup = match backup {
+        Ok(b) => Some(b),
+        Err(e) => {
+            ui.ui_print(&format!("Warning: could not back up super metadata, rollback disabled: {:#}", e))?;
+            None
+        }
+    };
+
+    let incremental = !ops.remove_all_groups && ops.groups.is_empty();
+    let result = if incremental {
+        incremental_update(ui, &ops)
     } else {
-        let info = parse_lpdump()?;
-        full_flash(ui, &info, &ops)?;
+        info.and_then(|info| full_flash(ui, &info, &ops))
+    };
+
+    if let Err(e) = result {
+        if let Some(backup) = backup {
+            ui.ui_print(&format!("Updating partitions failed: {:#}", e))?;
+            if let Err(rollback_err) = rollback_metadata(ui, &backup) {
+                ui.ui_print(&format!("Rollback FAILED: {:#}", rollback_err))?;
+                ui.ui_print(&format!("Restore {} to super manually before rebooting!", LP_BACKUP_PATHS[0]))?;
+            }
+        }
+        return Err(e);
+    }
+
+    if !incremental {
         let tool_path = "/tmp/lptools/lptools";
-        for (part, _size) in &ops.sizes {
+        for part in ops.sizes.keys() {
             let _ = exec_binary(ui, tool_path, &["map", part]);
         }
     }
@@ -60,18 +95,118 @@ pub fn update_dynamic_partitions(
     Ok(())
 }
 
+/// Copy of the LP metadata region of super, taken before it is modified.
+struct MetadataBackup {
+    data: Vec<u8>,
+    mapped: Vec<String>,
+}
+
+/// Saves the whole LP metadata region of super (geometry plus primary and
+/// backup metadata for every slot) to `/tmp` and `/sdcard`, along with the
+/// list of currently mapped partitions.
+fn backup_metadata(ui: &mut RecoveryUI, info: &SuperInfo) -> Result<MetadataBackup> {
+    if info.metadata_size == 0 || info.metadata_slots == 0 {
+        bail!("lpdump did not report the metadata layout");
+    }
+    let computed = LP_PARTITION_RESERVED_BYTES
+        + 2 * LP_METADATA_GEOMETRY_SIZE
+        + 2 * info.metadata_size * info.metadata_slots as u64;
+    let len = info.first_sector.max(computed);
+
+    let mut data = vec![0u8; len as usize];
+    File::open(SUPER_DEVICE)
+        .context(format!("Failed to open {}", SUPER_DEVICE))?
+        .read_exact(&mut data)?;
+
+    let mut saved = Vec::new();
+    for path in LP_BACKUP_PATHS {
+        let written = Path::new(path)
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, &data));
+        if written.is_ok() {
+            saved.push(path);
+        }
+    }
+    if saved.is_empty() {
+        bail!("Failed to write metadata backup");
+    }
+    ui.ui_print(&format!("Backed up super metadata to {}", saved.join(", ")))?;
+
+    Ok(MetadataBackup { data, mapped: mapped_partitions() })
+}
+
+/// Writes the saved metadata back to super and remaps the partitions that
+/// were mapped before the update.
+fn rollback_metadata(ui: &mut RecoveryUI, backup: &MetadataBackup) -> Result<()> {
+    let tool = "/tmp/lptools/lptools";
+    ui.ui_print("Rolling back super metadata...")?;
+
+    for name in mapped_partitions() {
+        let _ = exec_binary(ui, tool, &["unmap", &name]);
+    }
+
+    let mut device = OpenOptions::new()
+        .write(true)
+        .open(SUPER_DEVICE)
+        .context(format!("Failed to open {}", SUPER_DEVICE))?;
+    device.write_all(&backup.data)?;
+    device.sync_all()?;
+    ui.ui_print(&format!("  Restored {} bytes of metadata (all slots)", backup.data.len()))?;
+
+    let mut remapped = Vec::new();
+    for name in &backup.mapped {
+        let status = Command::new(tool)
+            .args(["map", name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if status.is_ok_and(|s| s.success()) {
+            remapped.push(name.as_str());
+        } else {
+            ui.ui_print(&format!("  Warning: failed to remap {}", name))?;
+        }
+    }
+    ui.ui_print(&format!("  Remapped: {}", remapped.join(", ")))?;
+    Ok(())
+}
+
+/// Logical partitions currently mapped under `/dev/block/mapper`.
+fn mapped_partitions() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(entries) = fs::read_dir("/dev/block/mapper") {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|ft| !ft.is_dir())
+                && let Some(name) = entry.file_name().to_str()
+                && !matches!(name, "control" | "userdata" | "metadata")
+            {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    names
+}
+
+/// Runs an lptools step the update can't do without, failing (and so rolling
+/// back the metadata) if it does.
+fn lptools_step(tool: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(tool).args(args).output().context(format!("Failed to exec {}", tool))?;
+    if !output.status.success() {
+        bail!("lptools {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
 fn incremental_update(ui: &mut RecoveryUI, ops: &OpList) -> Result<()> {
     let tool = "/tmp/lptools/lptools";
     
     for (part, size) in &ops.sizes {
+        // The partition may not be mapped or may not exist yet.
         let _ = exec_binary(ui, tool, &["unmap", part]);
         let _ = exec_binary(ui, tool, &["remove", part]);
-        let _ = exec_binary(ui, tool, &["create", part, size]);
-        let _ = Command::new(tool)
-            .args(&["map", part])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
+        lptools_step(tool, &["create", part, size])?;
+        lptools_step(tool, &["map", part])?;
     }
     
     Ok(())
@@ -84,30 +219,16 @@ fn full_flash(ui: &mut RecoveryUI, info: &SuperInfo, ops: &OpList) -> Result<()>
     if ops.remove_all_groups {
         let _ = exec_binary(ui, lptools_bin, &["clear-cow"]);
 
-        if let Ok(entries) = fs::read_dir("/dev/block/mapper") {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    if let Ok(ft) = entry.file_type() {
-                        if !ft.is_dir() {
-                            let path = entry.path();
-                            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                                 if name == "control" { continue; }
-                                 if name == "userdata" { continue; }
-                                 if name == "metadata" { continue; }
-                                 let _ = exec_binary(ui, lptools_bin, &["unmap", name]);
-                                 let _ = exec_binary(ui, lptools_bin, &["remove", name]);
-                            }
-                        }
-                    }
-                }
-            }
+        for name in mapped_partitions() {
+            let _ = exec_binary(ui, lptools_bin, &["unmap", &name]);
+            let _ = exec_binary(ui, lptools_bin, &["remove", &name]);
         }
     }
 
     let placeholder = "/tmp/placeholder.img";
     if !Path::new(placeholder).exists() {
         Command::new("dd")
-            .args(&["if=/dev/zero", &format!("of={}", placeholder), "bs=1M", "count=1"])
+            .args(["if=/dev/zero", &format!("of={}", placeholder), "bs=1M", "count=1"])
             .status()?;
     }
 
@@ -130,7 +251,7 @@ fn full_flash(ui: &mut RecoveryUI, info: &SuperInfo, ops: &OpList) -> Result<()>
             let size = resolve_size(g_size);
             args.push(format!("--group={}:{}", g_name, size));
         }
-        for (p_name, _p_group) in &ops.partitions {
+        for p_name in ops.partitions.keys() {
             if let Some(size) = ops.sizes.get(p_name) {
                  let group = ops.partitions.get(p_name).unwrap();
                  args.push(format!("--partition={}:none:{}:{}", p_name, size, group));
@@ -165,19 +286,19 @@ fn full_flash(ui: &mut RecoveryUI, info: &SuperInfo, ops: &OpList) -> Result<()>
             let size = resolve_size(g_size);
             args.push(format!("--group={}:{}", group_tbl_active, size));
         }
-        if !group_tbl_inactive.is_empty() {
-             if let Some(g_size) = ops.groups.get(&group_tbl_inactive) {
-                let size = resolve_size(g_size);
-                args.push(format!("--group={}:{}", group_tbl_inactive, size));
-            }
+        if !group_tbl_inactive.is_empty()
+            && let Some(g_size) = ops.groups.get(&group_tbl_inactive)
+        {
+            let size = resolve_size(g_size);
+            args.push(format!("--group={}:{}", group_tbl_inactive, size));
         }
 
         for (p_name, group) in &ops.partitions {
-            if p_name.contains(slot) {
-                 if let Some(size) = ops.sizes.get(p_name) {
-                     args.push(format!("--partition={}:none:{}:{}", p_name, size, group));
-                     args.push(format!("--image={}={}", p_name, placeholder));
-                 }
+            if p_name.contains(slot)
+                && let Some(size) = ops.sizes.get(p_name)
+            {
+                args.push(format!("--partition={}:none:{}:{}", p_name, size, group));
+                args.push(format!("--image={}={}", p_name, placeholder));
             }
         }
 
@@ -193,7 +314,7 @@ fn full_flash(ui: &mut RecoveryUI, info: &SuperInfo, ops: &OpList) -> Result<()>
         bail!("Meta slot count {} is not supported!", info.metadata_slots);
     }
 
-    args.push("--output=/dev/block/by-name/super".to_string());
+    args.push(format!("--output={}", SUPER_DEVICE));
     
     let mut cmd = Command::new(lpmake_bin);
     for arg in args {
@@ -244,8 +365,10 @@ fn parse_lpdump() -> Result<SuperInfo> {
         info.first_sector = sector * 512; 
     }
     if let Some(caps) = re_size.captures(&stdout) { info.size = caps[1].parse()?; }
-    if let Some(caps) = re_flags.captures(&stdout) {
-        if &caps[1] == "virtual_ab_device" { info.is_virtual_ab = true; }
+    if let Some(caps) = re_flags.captures(&stdout)
+        && &caps[1] == "virtual_ab_device"
+    {
+        info.is_virtual_ab = true;
     }
     Ok(info)
 }
@@ -0,0 +1,290 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{PermissionsExt, lchown, symlink as unix_symlink};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+
+use crate::recovery::RecoveryUI;
+
+const XATTR_SELINUX: &str = "security.selinux";
+const XATTR_CAPS: &str = "security.capability";
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x1;
+
+/// Parses a number like edify's `strtoul(s, 0)`: `0x` is hex, a leading `0`
+/// octal, anything else decimal. Modes must be written `0755`.
+fn parse_number(value: &str) -> Result<u64> {
+    let v = value.trim();
+    let parsed = if let Some(hex) = v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if v.len() > 1 && v.starts_with('0') {
+        u64::from_str_radix(&v[1..], 8)
+    } else {
+        v.parse()
+    };
+    parsed.context(format!("Invalid number: {}", value))
+}
+
+fn parse_id(value: &str) -> Result<u32> {
+    u32::try_from(parse_number(value)?).context(format!("Invalid uid/gid: {}", value))
+}
+
+fn parse_mode(value: &str) -> Result<u32> {
+    let mode = parse_number(value)?;
+    if mode > 0o7777 { bail!("Invalid mode: {}", value); }
+    Ok(mode as u32)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, CString::new(name).unwrap());
+    let ret = unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+fn remove_xattr(path: &Path, name: &str) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, CString::new(name).unwrap());
+    if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::ENODATA) { Ok(()) } else { Err(err) }
+}
+
+/// `security.capability` as `vfs_cap_data` revision 2: permitted and
+/// effective set to `caps`, nothing inheritable.
+fn vfs_cap_data(caps: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(20);
+    for value in [VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE, caps as u32, 0, (caps >> 32) as u32, 0] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data
+}
+
+/// What `set_metadata` applies to a file. Unset fields are left alone.
+#[derive(Default)]
+struct Metadata {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+    dir_mode: Option<u32>,
+    file_mode: Option<u32>,
+    capabilities: Option<u64>,
+    selabel: Option<String>,
+}
+
+impl Metadata {
+    /// Parses edify's `"key", value` pairs.
+    fn parse(pairs: &[String], recursive: bool) -> Result<Self> {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            bail!("set_metadata expects <key> <value> pairs");
+        }
+        let mut meta = Metadata::default();
+        for pair in pairs.chunks(2) {
+            let (key, value) = (pair[0].as_str(), pair[1].as_str());
+            match key {
+                "uid" => meta.uid = Some(parse_id(value)?),
+                "gid" => meta.gid = Some(parse_id(value)?),
+                "mode" if !recursive => meta.mode = Some(parse_mode(value)?),
+                "dmode" if recursive => meta.dir_mode = Some(parse_mode(value)?),
+                "fmode" if recursive => meta.file_mode = Some(parse_mode(value)?),
+                "capabilities" => meta.capabilities = Some(parse_number(value)?),
+                "selabel" => meta.selabel = Some(value.to_string()),
+                _ => bail!("Unknown set_metadata key: {}", key),
+            }
+        }
+        Ok(meta)
+    }
+
+    /// Applies in edify's order: owner, mode, SELinux label, then
+    /// capabilities (chown clears them). Symlinks only get owner and label.
+    fn apply(&self, path: &Path) -> Result<()> {
+        let meta = fs::symlink_metadata(path).context(format!("{} not found", path.display()))?;
+        let is_link = meta.file_type().is_symlink();
+
+        if self.uid.is_some() || self.gid.is_some() {
+            lchown(path, self.uid, self.gid).context(format!("Failed to chown {}", path.display()))?;
+        }
+        let mode = if meta.is_dir() { self.dir_mode.or(self.mode) } else { self.file_mode.or(self.mode) };
+        if let Some(mode) = mode.filter(|_| !is_link) {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .context(format!("Failed to chmod {}", path.display()))?;
+        }
+        if let Some(label) = &self.selabel {
+            let mut value = label.as_bytes().to_vec();
+            value.push(0);
+            set_xattr(path, XATTR_SELINUX, &value).context(format!("Failed to set SELinux label of {}", path.display()))?;
+        }
+        if let Some(caps) = self.capabilities.filter(|_| !is_link && meta.is_file()) {
+            let result = if caps == 0 { remove_xattr(path, XATTR_CAPS) } else { set_xattr(path, XATTR_CAPS, &vfs_cap_data(caps)) };
+            result.context(format!("Failed to set capabilities of {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn apply_recursive(&self, path: &Path) -> Result<usize> {
+        self.apply(path)?;
+        let mut count = 1;
+        if fs::symlink_metadata(path)?.is_dir() {
+            for entry in fs::read_dir(path).context(format!("Failed to read {}", path.display()))? {
+                count += self.apply_recursive(&entry?.path())?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// `delete <path...>`: deletes files and symlinks, missing ones are skipped.
+pub fn delete(ui: &mut RecoveryUI, paths: &[String]) -> Result<()> {
+    let mut deleted = 0;
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => deleted += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => ui.ui_print(&format!("Warning: failed to delete {}: {}", path, e))?,
+        }
+    }
+    ui.ui_print(&format!("Deleted {} of {} files", deleted, paths.len()))?;
+    Ok(())
+}
+
+/// `delete_recursive <dir...>`: `rm -rf` each directory.
+pub fn delete_recursive(ui: &mut RecoveryUI, paths: &[String]) -> Result<()> {
+    for path in paths {
+        let result = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+            Ok(_) => fs::remove_file(path),
+            Err(_) => continue,
+        };
+        if let Err(e) = result {
+            ui.ui_print(&format!("Warning: failed to delete {}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// `symlink <target> <link...>`: creates each link pointing at `<target>`,
+/// replacing whatever is there and creating missing parent directories.
+pub fn symlink(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    if args.len() < 2 { bail!("symlink requires <target> <link...>"); }
+    let target = &args[0];
+    let mut failed = 0;
+    for link in &args[1..] {
+        let link = Path::new(link);
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(link).is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(link)?;
+        }
+        if let Err(e) = unix_symlink(target, link) {
+            ui.ui_print(&format!("Failed to symlink {} to {}: {}", link.display(), target, e))?;
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        let msg = format!("symlink: {} links could not be created. Aborting...", failed);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
+
+/// `set_perm <uid> <gid> <mode> <path...>`
+pub fn set_perm(args: &[String]) -> Result<()> {
+    if args.len() < 4 { bail!("set_perm requires <uid> <gid> <mode> <path...>"); }
+    let meta = Metadata {
+        uid: Some(parse_id(&args[0])?),
+        gid: Some(parse_id(&args[1])?),
+        mode: Some(parse_mode(&args[2])?),
+        ..Default::default()
+    };
+    for path in &args[3..] {
+        meta.apply(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// `set_perm_recursive <uid> <gid> <dirmode> <filemode> <dir...>`
+pub fn set_perm_recursive(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    if args.len() < 5 { bail!("set_perm_recursive requires <uid> <gid> <dirmode> <filemode> <dir...>"); }
+    let meta = Metadata {
+        uid: Some(parse_id(&args[0])?),
+        gid: Some(parse_id(&args[1])?),
+        dir_mode: Some(parse_mode(&args[2])?),
+        file_mode: Some(parse_mode(&args[3])?),
+        ..Default::default()
+    };
+    for dir in &args[4..] {
+        let count = meta.apply_recursive(Path::new(dir))?;
+        ui.ui_print(&format!("Set permissions on {} ({} entries)", dir, count))?;
+    }
+    Ok(())
+}
+
+/// `set_metadata <path> <key> <value>...` with keys `uid`, `gid`, `mode`,
+/// `capabilities` and `selabel`.
+pub fn set_metadata(args: &[String]) -> Result<()> {
+    let path = args.first().context("set_metadata requires <path> <key> <value>...")?;
+    Metadata::parse(&args[1..], false)?.apply(Path::new(path))
+}
+
+/// `set_metadata_recursive <dir> <key> <value>...` with `dmode`/`fmode`
+/// instead of `mode`.
+pub fn set_metadata_recursive(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    let dir = args.first().context("set_metadata_recursive requires <dir> <key> <value>...")?;
+    let count = Metadata::parse(&args[1..], true)?.apply_recursive(Path::new(dir))?;
+    ui.ui_print(&format!("Set metadata on {} ({} entries)", dir, count))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_like_strtoul() {
+        assert_eq!(parse_number("0755").unwrap(), 0o755);
+        assert_eq!(parse_number("0x1F").unwrap(), 31);
+        assert_eq!(parse_number("0X10").unwrap(), 16);
+        assert_eq!(parse_number(" 1000 ").unwrap(), 1000);
+        assert_eq!(parse_number("0").unwrap(), 0);
+        assert!(parse_number("0789").is_err());
+        assert!(parse_number("-1").is_err());
+        assert!(parse_number("").is_err());
+    }
+
+    #[test]
+    fn modes_and_ids() {
+        assert_eq!(parse_mode("06755").unwrap(), 0o6755);
+        assert!(parse_mode("010000").is_err());
+        assert_eq!(parse_id("2000").unwrap(), 2000);
+        assert!(parse_id("0x100000000").is_err());
+    }
+
+    #[test]
+    fn metadata_keys() {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let meta = Metadata::parse(&args(&["uid", "0", "mode", "0644", "capabilities", "0x1000", "selabel", "u:object_r:system_file:s0"]), false).unwrap();
+        assert_eq!((meta.uid, meta.mode, meta.capabilities), (Some(0), Some(0o644), Some(0x1000)));
+        assert_eq!(meta.selabel.as_deref(), Some("u:object_r:system_file:s0"));
+
+        assert!(Metadata::parse(&args(&["mode", "0644"]), true).is_err());
+        assert!(Metadata::parse(&args(&["dmode", "0755"]), false).is_err());
+        assert!(Metadata::parse(&args(&["uid"]), false).is_err());
+        assert_eq!(Metadata::parse(&args(&["dmode", "0755", "fmode", "0644"]), true).unwrap().dir_mode, Some(0o755));
+    }
+
+    #[test]
+    fn capability_data() {
+        let data = vfs_cap_data((1 << 40) | 0x3000);
+        assert_eq!(data.len(), 20);
+        assert_eq!(data[..4], 0x0200_0001u32.to_le_bytes());
+        assert_eq!(data[4..8], 0x3000u32.to_le_bytes());
+        assert_eq!(data[12..16], 0x100u32.to_le_bytes());
+    }
+}
@@ -1,47 +1,129 @@
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::process::Command;
+use std::sync::RwLock;
 use anyhow::{Context, Result, bail};
-use zip::ZipArchive;
+use zip::{CompressionMethod, ZipArchive};
+use byteorder::{LittleEndian, ReadBytesExt};
 use std::os::unix::fs::PermissionsExt;
 use md5::{Md5, Digest};
+use sha1::Sha1;
+use sha2::Sha256;
 
+use crate::blockdev::HoleMode;
+use crate::parallel::Abortable;
 use crate::recovery::RecoveryUI;
 use crate::threaded_writer::ThreadedWriter;
 
 const WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+const MAX_VERIFY_THREADS: usize = 4;
+pub const DIFF_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Where system properties are read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropSource {
+    /// `androidboot.*` from `/proc/cmdline` and `/proc/bootconfig`, as `ro.boot.*`.
+    Cmdline,
+    /// A `build.prop`-style `key=value` file.
+    File(String),
+    /// The `getprop` binary, if the recovery has one.
+    Getprop,
+}
 
-pub fn verify_device(ui: &mut RecoveryUI, allowed_devices: &str) -> Result<()> {
-    let output = Command::new("getprop")
-        .arg("ro.product.device")
-        .output()?;
-    let current_device = String::from_utf8(output.stdout)?.trim().to_string();
-    
-    let allowed: Vec<&str> = allowed_devices.split(',').map(|s| s.trim()).collect();
-
-    let mut valid = false;
-    for &d in &allowed {
-        if current_device == d || current_device.contains(d) {
-            valid = true;
-            break;
+impl PropSource {
+    /// `cmdline`, `getprop`, or a path to a prop file.
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "cmdline" => PropSource::Cmdline,
+            "getprop" => PropSource::Getprop,
+            path => PropSource::File(path.to_string()),
         }
     }
 
-    if !valid {
-         let output_build = Command::new("getprop").arg("ro.build.product").output()?;
-         let current_product = String::from_utf8(output_build.stdout)?.trim().to_string();
-         for &d in &allowed {
-            if current_product == d || current_product.contains(d) {
-                valid = true;
-                break;
-            }
+    fn lookup(&self, name: &str) -> Option<String> {
+        match self {
+            PropSource::Getprop => Command::new("getprop")
+                .arg(name)
+                .output()
+                .ok()
+                .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string()),
+            PropSource::Cmdline => {
+                let key = format!("androidboot.{}", name.strip_prefix("ro.boot.")?);
+                let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+                let from_cmdline = cmdline
+                    .split_whitespace()
+                    .filter_map(|param| param.split_once('='))
+                    .rfind(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string());
+                from_cmdline.or_else(|| {
+                    // bootconfig: `androidboot.slot_suffix = "_a"`
+                    fs::read_to_string("/proc/bootconfig").ok()?.lines().find_map(|line| {
+                        let (k, v) = line.split_once('=')?;
+                        (k.trim() == key).then(|| v.trim().trim_matches('"').to_string())
+                    })
+                })
+            },
+            PropSource::File(path) => {
+                let content = fs::read_to_string(path).ok()?;
+                let mut values = content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.starts_with('#'))
+                    .filter_map(|l| l.split_once('='))
+                    .filter(|(k, _)| k.trim() == name)
+                    .map(|(_, v)| v.trim().to_string());
+                // Like init: the first definition of a read-only prop wins.
+                if name.starts_with("ro.") { values.next() } else { values.next_back() }
+            },
         }
     }
-    
+}
+
+/// Lookup order used until `set_prop_sources` is called.
+fn default_prop_sources() -> Vec<PropSource> {
+    vec![
+        PropSource::Getprop,
+        PropSource::Cmdline,
+        PropSource::File("/prop.default".to_string()),
+        PropSource::File("/default.prop".to_string()),
+        PropSource::File("/system/build.prop".to_string()),
+    ]
+}
+
+/// Lookup order set by the script; empty means `default_prop_sources`.
+static PROP_SOURCES: RwLock<Vec<PropSource>> = RwLock::new(Vec::new());
+
+/// Changes where `getprop` looks, first source with a non-empty value wins.
+pub fn set_prop_sources(sources: Vec<PropSource>) {
+    *PROP_SOURCES.write().unwrap_or_else(|e| e.into_inner()) = sources;
+}
+
+/// Value of a system property, or an empty string if it isn't set.
+pub fn getprop(name: &str) -> String {
+    let configured = PROP_SOURCES.read().unwrap_or_else(|e| e.into_inner()).clone();
+    let sources = if configured.is_empty() { default_prop_sources() } else { configured };
+    sources
+        .iter()
+        .filter_map(|source| source.lookup(name))
+        .find(|value| !value.is_empty())
+        .unwrap_or_default()
+}
+
+pub fn verify_device(ui: &mut RecoveryUI, allowed_devices: &str) -> Result<()> {
+    let allowed: Vec<&str> = allowed_devices.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
+
+    let current_device = getprop("ro.product.device");
+    let current_product = getprop("ro.build.product");
+    let valid = allowed.iter().any(|&d| d == current_device || d == current_product);
+
     if !valid {
-        ui.ui_print("This ROM is not compatible for your device! aborting...")?;
-        std::process::exit(1);
+        let msg = format!(
+            "This ROM is not compatible for your device ({})! aborting...",
+            if current_device.is_empty() { &current_product } else { &current_device }
+        );
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
     }
 
     Ok(())
@@ -81,7 +163,7 @@ pub fn package_extract_file(
     }
 
     let mut writer = ThreadedWriter::new(dest_path.to_string(), WRITE_BUFFER_SIZE);
-    io::copy(&mut entry, &mut writer)?;
+    io::copy(&mut Abortable(&mut entry), &mut writer)?;
     writer.finish()?;
     Ok(())
 }
@@ -100,33 +182,217 @@ pub fn package_extract_targz(
     Ok(())
 }
 
+/// `--option` flags accepted by `package_flash_partition`, anywhere in its
+/// argument list.
+#[derive(Default)]
+pub struct FlashOptions {
+    /// `--discard` / `--zeroout`: how sparse holes are handled.
+    pub holes: HoleMode,
+    /// `--wipe`: discard the whole partition before flashing.
+    pub wipe: bool,
+    /// `--direct`: write with `O_DIRECT` (zstd and gzip methods).
+    pub direct: bool,
+    /// `--diff`: only write blocks that differ from the partition's contents.
+    pub diff: bool,
+    /// `--slot=`: treat the destination as a partition name on these slots.
+    pub slot: Option<SlotTarget>,
+}
+
+/// Which A/B slot(s) a `--slot=` option writes to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SlotTarget {
+    Active,
+    Inactive,
+    Both,
+}
+
+impl SlotTarget {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "active" => Ok(SlotTarget::Active),
+            "inactive" => Ok(SlotTarget::Inactive),
+            "both" => Ok(SlotTarget::Both),
+            _ => bail!("Invalid --slot value: {} (expected active, inactive or both)", value),
+        }
+    }
+}
+
+/// `_a` for `_b` and the other way around. Empty on non-A/B devices.
+pub fn inactive_slot_suffix(active: &str) -> String {
+    match active {
+        "_a" => "_b".to_string(),
+        "_b" => "_a".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Expands a partition for `--slot=` into one path per targeted slot.
+pub fn resolve_slot_targets(dest: &str, slot: SlotTarget) -> Result<Vec<String>> {
+    let active = get_active_slot_suffix().unwrap_or_default();
+    if active.is_empty() && slot != SlotTarget::Active {
+        bail!("--slot=inactive/both needs an A/B device, but no slot suffix was found");
+    }
+    let suffixes = match slot {
+        SlotTarget::Active => vec![active],
+        SlotTarget::Inactive => vec![inactive_slot_suffix(&active)],
+        SlotTarget::Both => vec!["_a".to_string(), "_b".to_string()],
+    };
+
+    Ok(suffixes.iter().map(|suffix| slot_partition_path(dest, suffix)).collect())
+}
+
+/// `boot` + `_a` -> `/dev/block/by-name/boot_a`. Paths only get the suffix.
+pub fn slot_partition_path(name: &str, suffix: &str) -> String {
+    if name.contains('/') {
+        format!("{}{}", name, suffix)
+    } else {
+        format!("/dev/block/by-name/{}{}", name, suffix)
+    }
+}
+
+/// Pulls a `--slot=` option out of `args`.
+fn split_slot_option(args: &[String]) -> Result<(Vec<String>, Option<SlotTarget>)> {
+    let mut rest = Vec::new();
+    let mut slot = None;
+    for arg in args {
+        match arg.strip_prefix("--slot=") {
+            Some(value) => slot = Some(SlotTarget::parse(value)?),
+            None => rest.push(arg.clone()),
+        }
+    }
+    Ok((rest, slot))
+}
+
+/// `package_extract_file <file> <dest> [--slot=active|inactive|both]`.
+pub fn package_extract_file_cmd(archive: &mut ZipArchive<File>, args: &[String]) -> Result<()> {
+    let (args, slot) = split_slot_option(args)?;
+    if args.len() < 2 { bail!("package_extract_file requires <file> <dest_path>"); }
+
+    match slot {
+        Some(slot) => {
+            for dest in resolve_slot_targets(&args[1], slot)? {
+                package_extract_file(archive, &args[0], &dest)?;
+            }
+            Ok(())
+        },
+        None => package_extract_file(archive, &args[0], &args[1]),
+    }
+}
+
+pub fn parse_flash_options(args: &[String]) -> Result<(Vec<String>, FlashOptions)> {
+    let (args, slot) = split_slot_option(args)?;
+    let mut positional = Vec::new();
+    let mut opts = FlashOptions { slot, ..Default::default() };
+
+    for arg in &args {
+        match arg.as_str() {
+            "--discard" => opts.holes = HoleMode::Discard,
+            "--zeroout" => opts.holes = HoleMode::ZeroOut,
+            "--wipe" => opts.wipe = true,
+            "--direct" => opts.direct = true,
+            "--diff" => opts.diff = true,
+            a if a.starts_with("--") => bail!("Unknown flash option: {}", a),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if opts.diff && (opts.wipe || opts.direct || opts.holes != HoleMode::Seek) {
+        bail!("--diff can't be combined with --wipe, --direct, --discard or --zeroout");
+    }
+    Ok((positional, opts))
+}
+
 pub fn package_flash_partition(
     ui: &mut RecoveryUI,
     archive: &mut ZipArchive<File>,
+    zip_path: &str,
     args: &[String]
 ) -> Result<()> {
+    let (mut args, opts) = parse_flash_options(args)?;
     if args.is_empty() { return Ok(()); }
-    
+
+    let Some(slot) = opts.slot else {
+        return flash_one(ui, archive, zip_path, &args, &opts);
+    };
+    let dest_index = destination_index(&args);
+    let partition = args.get(dest_index).context("Missing destination arg")?.clone();
+    for dest in resolve_slot_targets(&partition, slot)? {
+        ui.ui_print(&format!("Flashing {} to {}...", args[1], dest))?;
+        args[dest_index] = dest;
+        flash_one(ui, archive, zip_path, &args, &opts)?;
+    }
+    Ok(())
+}
+
+/// Everything a `package_flash_partition` or `package_extract_file` command
+/// writes to, with `--slot=` resolved.
+pub fn write_targets(cmd: &str, args: &[String]) -> Result<Vec<String>> {
+    let (dest, slot) = match cmd {
+        "package_flash_partition" => {
+            let (args, opts) = parse_flash_options(args)?;
+            if args.is_empty() { return Ok(Vec::new()); }
+            let dest = args.get(destination_index(&args)).context("Missing destination arg")?.clone();
+            (dest, opts.slot)
+        },
+        _ => {
+            let (args, slot) = split_slot_option(args)?;
+            (args.get(1).context(format!("{} requires <file> <dest_path>", cmd))?.clone(), slot)
+        },
+    };
+    match slot {
+        Some(slot) => resolve_slot_targets(&dest, slot),
+        None => Ok(vec![dest]),
+    }
+}
+
+/// Position of the destination in `package_flash_partition` args. It is the
+/// third one, except in the sparse range form `2 <img> <start> <end> <dest>`.
+fn destination_index(args: &[String]) -> usize {
+    let sparse_range = args[0] == "2"
+        && args.get(1).is_some_and(|entry| !entry.ends_with(".*"))
+        && args.get(2).is_some_and(|a| !a.is_empty() && a.chars().all(|c| c.is_numeric()));
+    if sparse_range { 4 } else { 2 }
+}
+
+fn flash_one(
+    ui: &mut RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    zip_path: &str,
+    args: &[String],
+    opts: &FlashOptions
+) -> Result<()> {
     let method = &args[0];
     let zip_entry = args.get(1).context("Missing zip entry arg")?;
     match method.as_str() {
         "0" => {
             let dest_path = args.get(2).context("Missing destination arg")?;
-            let mut source = archive.by_name(zip_entry)?;
-            let mut writer = ThreadedWriter::new(dest_path.to_string(), WRITE_BUFFER_SIZE);
+            if let Some(size) = zstd_content_size(archive, zip_entry)? {
+                crate::blockdev::ensure_fits(ui, zip_entry, size, dest_path)?;
+            }
+            if opts.wipe {
+                crate::blockdev::discard_all(ui, dest_path)?;
+            }
+            let mut source = Abortable(archive.by_name(zip_entry)?);
+            let mut writer = flash_writer(dest_path, opts);
             zstd::stream::copy_decode(&mut source, &mut writer)?;
-            writer.finish()?;
+            finish_flash(ui, writer, opts)?;
         },
         "1" => {
             let dest_path = args.get(2).context("Missing destination arg")?;
+            if let Some(size) = gzip_isize(archive, zip_path, zip_entry)? {
+                crate::blockdev::ensure_fits(ui, zip_entry, size, dest_path)?;
+            }
+            if opts.wipe {
+                crate::blockdev::discard_all(ui, dest_path)?;
+            }
             let source = archive.by_name(zip_entry)?;
-            let mut writer = ThreadedWriter::new(dest_path.to_string(), WRITE_BUFFER_SIZE);
-            let mut decoder = flate2::read::GzDecoder::new(source);
+            let mut writer = flash_writer(dest_path, opts);
+            let mut decoder = flate2::read::GzDecoder::new(Abortable(source));
             io::copy(&mut decoder, &mut writer)?;
-            writer.finish()?;
+            finish_flash(ui, writer, opts)?;
         },
         "2" => {
-            crate::sparse::flash_sparse(ui, archive, args)?;
+            crate::sparse::flash_sparse(ui, archive, args, opts)?;
         },
         _ => {
             ui.ui_print(&format!("Unknown flash method: {}", method))?;
@@ -135,63 +401,57 @@ pub fn package_flash_partition(
     Ok(())
 }
 
-pub fn disable_vbmeta(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>) -> Result<()> {
-    let mut bin_path = "/system/bin/avbctl".to_string();
-
-    if !Path::new(&bin_path).exists() {
-        if Path::new("/sbin/avbctl").exists() {
-             bin_path = "/sbin/avbctl".to_string();
-        } else {
-            ui.ui_print("avbctl binary not found. Extracting from zip...")?;
-            
-            let tmp_path = "/tmp/avbctl";
-            package_extract_file(archive, "META-INF/bin/avbctl", tmp_path)?;
-            let mut perms = fs::metadata(tmp_path)?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(tmp_path, perms)?;
-
-            bin_path = tmp_path.to_string();
-        }
+fn flash_writer(dest_path: &str, opts: &FlashOptions) -> ThreadedWriter {
+    if opts.diff {
+        ThreadedWriter::new_diff(dest_path.to_string(), DIFF_BLOCK_SIZE)
+    } else if opts.direct {
+        ThreadedWriter::new_direct(dest_path.to_string(), WRITE_BUFFER_SIZE)
+    } else {
+        ThreadedWriter::new(dest_path.to_string(), WRITE_BUFFER_SIZE)
     }
+}
 
-    ui.ui_print("Checking avb vbmeta status")?;
-
-    let verity = exec_capture("avbctl", &["get-verity"])?;
-    let verification = exec_capture("avbctl", &["get-verification"])?;
-
-    ui.ui_print(&format!("- {}", verity))?;
-    ui.ui_print(&format!("- {}", verification))?;
-
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    ui.ui_print(" ")?;
-
-    if verity.to_lowercase().contains("disabled") {
-        ui.ui_print("avb vbmeta already disabled, no need to continue")?;
+fn finish_flash(ui: &mut RecoveryUI, writer: ThreadedWriter, opts: &FlashOptions) -> Result<()> {
+    if opts.diff {
+        let stats = writer.finish_diff()?;
+        ui.ui_print(&stats.summary())?;
     } else {
-        ui.ui_print("Disabling avb vbmeta")?;
-
-        let dis_verity = exec_capture(&bin_path, &["--force", "disable-verity"])?;
-        let dis_verification = exec_capture(&bin_path, &["--force", "disable-verification"])?;
+        writer.finish()?;
+    }
+    Ok(())
+}
 
-        ui.ui_print(&format!("- {}", dis_verity))?;
-        ui.ui_print(&format!("- {}", dis_verification))?;
+/// Decompressed size from the first zstd frame header, if the encoder stored it.
+fn zstd_content_size(archive: &mut ZipArchive<File>, zip_entry: &str) -> Result<Option<u64>> {
+    let mut entry = archive.by_name(zip_entry)?;
+    let mut header = Vec::with_capacity(18);
+    (&mut entry).take(18).read_to_end(&mut header)?;
+    Ok(zstd::zstd_safe::get_frame_content_size(&header).ok().flatten())
+}
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        ui.ui_print(" ")?;
-    }
+/// Decompressed size from the gzip ISIZE trailer. Only available when the
+/// entry is stored uncompressed in the zip, since the trailer has to be read
+/// without inflating the whole stream. ISIZE is modulo 2^32, so this is a
+/// lower bound for images over 4 GiB.
+fn gzip_isize(archive: &mut ZipArchive<File>, zip_path: &str, zip_entry: &str) -> Result<Option<u64>> {
+    let (data_start, size) = {
+        let entry = archive.by_name(zip_entry)?;
+        if entry.compression() != CompressionMethod::Stored || entry.size() < 18 {
+            return Ok(None);
+        }
+        (entry.data_start(), entry.size())
+    };
 
-    Ok(())
+    let mut file = File::open(zip_path).context("Failed to open zip")?;
+    file.seek(SeekFrom::Start(data_start + size - 4))?;
+    Ok(Some(file.read_u32::<LittleEndian>()? as u64))
 }
 
 pub fn get_active_slot_suffix() -> Result<String> {
-    let output = Command::new("getprop")
-        .arg("ro.boot.slot_suffix")
-        .output()?;
-    let mut suffix = String::from_utf8(output.stdout)?.trim().to_string();
+    let mut suffix = getprop("ro.boot.slot_suffix");
 
     if suffix.is_empty() {
-         let output2 = Command::new("getprop").arg("ro.boot.slot").output()?;
-         let s = String::from_utf8(output2.stdout)?.trim().to_string();
+         let s = getprop("ro.boot.slot");
          if !s.is_empty() {
              suffix = format!("_{}", s);
          }
@@ -219,15 +479,13 @@ pub fn run_program(ui: &mut crate::recovery::RecoveryUI, args: &[String]) -> Res
     }
 
     let program = &args[0];
-    if fs::metadata(program).is_ok() {
-        if let Ok(meta) = fs::metadata(program) {
-            let mut perms = meta.permissions();
-            
-            perms.set_mode(0o755); 
-            
-            if let Err(e) = fs::set_permissions(program, perms) {
-                let _ = ui.ui_print(&format!("Warning: Could not auto-chmod {}: {}", program, e));
-            }
+    if let Ok(meta) = fs::metadata(program) {
+        let mut perms = meta.permissions();
+        
+        perms.set_mode(0o755); 
+        
+        if let Err(e) = fs::set_permissions(program, perms) {
+            let _ = ui.ui_print(&format!("Warning: Could not auto-chmod {}: {}", program, e));
         }
     }
 
@@ -253,40 +511,133 @@ pub fn run_program(ui: &mut crate::recovery::RecoveryUI, args: &[String]) -> Res
     Ok(())
 }
 
+#[derive(Clone, Copy)]
+enum HashKind {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashKind {
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(HashKind::Md5),
+            40 => Some(HashKind::Sha1),
+            64 => Some(HashKind::Sha256),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            HashKind::Md5 => "MD5",
+            HashKind::Sha1 => "SHA-1",
+            HashKind::Sha256 => "SHA-256",
+        }
+    }
+
+    fn hash_reader<R: Read>(self, reader: &mut R) -> Result<String> {
+        match self {
+            HashKind::Md5 => hash_reader::<Md5, R>(reader),
+            HashKind::Sha1 => hash_reader::<Sha1, R>(reader),
+            HashKind::Sha256 => hash_reader::<Sha256, R>(reader),
+        }
+    }
+}
+
+pub fn hash_reader<D: Digest, R: Read>(reader: &mut R) -> Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 1024 * 1024]; 
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid hex string");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(Into::into))
+        .collect()
+}
+
 pub fn verify_md5sum(
     ui: &mut crate::recovery::RecoveryUI,
     archive: &mut ZipArchive<File>,
     file_path: &str,
     md5_path: &str,
 ) -> Result<()> {
-    let mut expected_md5 = String::new();
+    verify_hashsum(ui, archive, file_path, md5_path, HashKind::Md5)
+}
+
+pub fn verify_sha1sum(
+    ui: &mut crate::recovery::RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    file_path: &str,
+    sha1_path: &str,
+) -> Result<()> {
+    verify_hashsum(ui, archive, file_path, sha1_path, HashKind::Sha1)
+}
+
+pub fn verify_sha256sum(
+    ui: &mut crate::recovery::RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    file_path: &str,
+    sha256_path: &str,
+) -> Result<()> {
+    verify_hashsum(ui, archive, file_path, sha256_path, HashKind::Sha256)
+}
+
+fn verify_hashsum(
+    ui: &mut crate::recovery::RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    file_path: &str,
+    hash_path: &str,
+    kind: HashKind,
+) -> Result<()> {
+    let mut expected_hash = String::new();
     {
-        let mut md5_entry = match archive.by_name(md5_path) {
+        let mut hash_entry = match archive.by_name(hash_path) {
             Ok(entry) => entry,
             Err(_) => {
-                let msg = format!("Verification failed: {} is missing from the ZIP.", md5_path);
+                let msg = format!("Verification failed: {} is missing from the ZIP.", hash_path);
                 let _ = ui.ui_print(&msg);
                 bail!("{}", msg);
             }
         };
-        md5_entry.read_to_string(&mut expected_md5)?;
+        hash_entry.read_to_string(&mut expected_hash)?;
     }
 
-    let expected_md5 = expected_md5
-        .trim()
+    let expected_hash = expected_hash
         .split_whitespace()
         .next()
         .unwrap_or("")
         .to_lowercase();
 
-    if expected_md5.is_empty() {
-        let msg = format!("Verification failed: {} is empty or invalid.", md5_path);
+    if expected_hash.is_empty() {
+        let msg = format!("Verification failed: {} is empty or invalid.", hash_path);
         let _ = ui.ui_print(&msg);
         bail!("{}", msg);
     }
 
-    let mut hasher = Md5::new();
-    {
+    let actual_hash = {
         let mut file_entry = match archive.by_name(file_path) {
             Ok(entry) => entry,
             Err(_) => {
@@ -295,28 +646,180 @@ pub fn verify_md5sum(
                 bail!("{}", msg);
             }
         };
-        
-        let mut buffer = vec![0u8; 1024 * 1024]; 
-        loop {
-            let bytes_read = file_entry.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+        kind.hash_reader(&mut file_entry)?
+    };
+
+    if expected_hash != actual_hash {
+        let msg = format!("{} mismatch for {}. ZIP is corrupted! Aborting...", kind.label(), file_path);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg); 
+    }
+    Ok(())
+}
+
+pub struct ManifestEntry {
+    index: usize,
+    pub name: String,
+    hash: String,
+    kind: HashKind,
+}
+
+/// Verifies every entry listed in a `md5sum`/`sha1sum`/`sha256sum`-style
+/// manifest. Entries are hashed in parallel, each worker reading through its
+/// own `ZipArchive` handle, and all mismatches are reported before aborting.
+pub fn verify_manifest(
+    ui: &mut crate::recovery::RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    zip_path: &str,
+    manifest_path: &str,
+) -> Result<()> {
+    let mut manifest = String::new();
+    {
+        let mut manifest_entry = match archive.by_name(manifest_path) {
+            Ok(entry) => entry,
+            Err(_) => {
+                let msg = format!("Verification failed: {} is missing from the ZIP.", manifest_path);
+                let _ = ui.ui_print(&msg);
+                bail!("{}", msg);
             }
-            hasher.update(&buffer[..bytes_read]);
-        }
+        };
+        manifest_entry.read_to_string(&mut manifest)?;
     }
 
-    let result = hasher.finalize();
-    
-    let mut actual_md5 = String::with_capacity(32);
-    for byte in result {
-        actual_md5.push_str(&format!("{:02x}", byte));
+    let entries = parse_manifest(manifest_path, &manifest)?;
+    verify_manifest_entries(ui, zip_path, manifest_path, entries)
+}
+
+pub fn parse_manifest(manifest_path: &str, manifest: &str) -> Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    for (line_no, line) in manifest.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue; }
+
+        let (hash, name) = match trimmed.split_once(char::is_whitespace) {
+            Some((hash, rest)) => (hash.to_lowercase(), rest.trim_start().trim_start_matches('*')),
+            None => bail!("Invalid line {} in {}: {}", line_no + 1, manifest_path, trimmed),
+        };
+        let kind = match HashKind::from_hex_len(hash.len()) {
+            Some(kind) if hash.chars().all(|c| c.is_ascii_hexdigit()) => kind,
+            _ => bail!("Invalid hash on line {} in {}: {}", line_no + 1, manifest_path, hash),
+        };
+
+        entries.push(ManifestEntry {
+            index: entries.len(),
+            name: name.to_string(),
+            hash,
+            kind,
+        });
     }
+    Ok(entries)
+}
 
-    if expected_md5 != actual_md5 {
-        let msg = format!("MD5 mismatch for {}. ZIP is corrupted! Aborting...", file_path);
+pub fn verify_manifest_entries(
+    ui: &mut crate::recovery::RecoveryUI,
+    zip_path: &str,
+    manifest_path: &str,
+    entries: Vec<ManifestEntry>,
+) -> Result<()> {
+    if entries.is_empty() {
+        let msg = format!("Verification failed: {} is empty or invalid.", manifest_path);
         let _ = ui.ui_print(&msg);
-        bail!("{}", msg); 
+        bail!("{}", msg);
+    }
+
+    ui.ui_print(&format!("Verifying {} files from {}...", entries.len(), manifest_path))?;
+
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .clamp(1, MAX_VERIFY_THREADS)
+        .min(entries.len());
+
+    let mut queues: Vec<Vec<ManifestEntry>> = (0..workers).map(|_| Vec::new()).collect();
+    for entry in entries {
+        queues[entry.index % workers].push(entry);
+    }
+
+    let mut handles = Vec::with_capacity(workers);
+    for queue in queues {
+        let zip_path = zip_path.to_string();
+        handles.push(std::thread::spawn(move || -> Result<Vec<(usize, String)>> {
+            let file = File::open(&zip_path).context("Failed to open zip")?;
+            let mut archive = ZipArchive::new(file)?;
+            let mut failures = Vec::new();
+
+            for entry in queue {
+                let actual = match archive.by_name(&entry.name) {
+                    Ok(mut file_entry) => entry.kind.hash_reader(&mut file_entry),
+                    Err(_) => {
+                        failures.push((entry.index, format!("{} not found in ZIP", entry.name)));
+                        continue;
+                    }
+                };
+                match actual {
+                    Ok(actual) if actual == entry.hash => {},
+                    Ok(_) => failures.push((entry.index, format!("{} mismatch for {}", entry.kind.label(), entry.name))),
+                    Err(e) => failures.push((entry.index, format!("Failed to read {}: {}", entry.name, e))),
+                }
+            }
+            Ok(failures)
+        }));
+    }
+
+    let mut failures = Vec::new();
+    for handle in handles {
+        let worker_failures = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("Verification worker panicked"))??;
+        failures.extend(worker_failures);
+    }
+    failures.sort_by_key(|(index, _)| *index);
+
+    if !failures.is_empty() {
+        for (_, msg) in &failures {
+            ui.ui_print(&format!("- {}", msg))?;
+        }
+        let msg = format!("{} file(s) failed verification. ZIP is corrupted! Aborting...", failures.len());
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prop_file_lookup() {
+        let path = std::env::temp_dir().join(format!("props-test-{}.prop", std::process::id()));
+        fs::write(&path, "# comment\nro.product.device=first\nro.product.device=second\n  persist.sys.x = a \npersist.sys.x=b\n#ro.hidden=1\nempty=\n").unwrap();
+        let source = PropSource::parse(path.to_str().unwrap());
+        let lookup = |name: &str| source.lookup(name);
+
+        assert_eq!(lookup("ro.product.device").as_deref(), Some("first"));
+        assert_eq!(lookup("persist.sys.x").as_deref(), Some("b"));
+        assert_eq!(lookup("empty").as_deref(), Some(""));
+        assert_eq!(lookup("ro.hidden"), None);
+        assert_eq!(lookup("ro.missing"), None);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lookup("ro.product.device"), None);
+    }
+
+    #[test]
+    fn prop_source_names() {
+        assert_eq!(PropSource::parse("cmdline"), PropSource::Cmdline);
+        assert_eq!(PropSource::parse("getprop"), PropSource::Getprop);
+        assert_eq!(PropSource::parse("/vendor/build.prop"), PropSource::File("/vendor/build.prop".to_string()));
+        assert_eq!(PropSource::Cmdline.lookup("ro.product.device"), None);
+    }
+
+    #[test]
+    fn flash_destination() {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(destination_index(&args(&["0", "boot.img.zst", "boot"])), 2);
+        assert_eq!(destination_index(&args(&["2", "super.img.*", "super"])), 2);
+        assert_eq!(destination_index(&args(&["2", "super.img", "0", "3", "super"])), 4);
+        assert_eq!(destination_index(&args(&["2", "vendor.img", "vendor"])), 2);
+    }
+}
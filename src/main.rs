@@ -5,19 +5,50 @@ use std::path::Path;
 use anyhow::{bail, Context, Result};
 use zip::ZipArchive;
 use std::collections::HashMap;
-use crate::func::get_active_slot_suffix;
+use std::time::Duration;
+use crate::func::{get_active_slot_suffix, inactive_slot_suffix, set_prop_sources, PropSource};
 
 mod recovery;
 mod func;
 mod sparse;
 mod dynamic_partitions;
 mod threaded_writer;
+mod blockdev;
+mod signature;
+mod parallel;
+mod backup;
+mod bootctrl;
+mod slot_copy;
+mod avb;
+mod bootimg;
+mod ramdisk;
+mod dtbo;
+mod props;
+mod mount;
+mod wipe;
+mod fileops;
 
 use recovery::RecoveryUI;
-use func::{verify_device, package_extract_file, package_extract_targz, package_flash_partition, set_slot};
+use func::{verify_device, package_extract_file_cmd, package_extract_targz, package_flash_partition};
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
+
+    // Host-side helpers, not used by recovery.
+    match args.get(1).map(String::as_str) {
+        Some("keygen") => {
+            let out = args.get(2).context("Usage: update-binary keygen <secret_key_out>")?;
+            return signature::keygen(out);
+        },
+        Some("sign") => {
+            if args.len() < 4 {
+                bail!("Usage: update-binary sign <zipfile> <secret_key>");
+            }
+            return signature::sign_zip(&args[2], &args[3]);
+        },
+        _ => {}
+    }
+
     if args.len() < 4 {
         bail!("Usage: update-binary <api> <fd> <zipfile>");
     }
@@ -32,8 +63,11 @@ fn main() -> Result<()> {
 
     let mut vars: HashMap<String, String> = HashMap::new();
     let slot_suffix = get_active_slot_suffix().unwrap_or_default();
+    vars.insert("INACTIVE_SLOT".to_string(), inactive_slot_suffix(&slot_suffix));
     vars.insert("SLOT".to_string(), slot_suffix);
 
+    let mut mounts = mount::Mounts::default();
+
     let script_path = Path::new("/tmp/updater-script");
     {
         let mut script_entry = archive.by_name("META-INF/com/google/android/updater-script")
@@ -44,8 +78,9 @@ fn main() -> Result<()> {
     
     let script_file = File::open(script_path)?;
     let reader = BufReader::new(script_file);
+    let mut lines = reader.lines();
 
-    for line_result in reader.lines() {
+    while let Some(line_result) = lines.next() {
         let line = line_result?;
         let trimmed = line.trim();
 
@@ -66,34 +101,55 @@ fn main() -> Result<()> {
         let cmd = &parts[0];
         let raw_args = &parts[1..];
 
-        let args: Vec<String> = raw_args.iter().map(|arg| {
-            let mut new_arg = arg.to_string();
-            for (key, val) in &vars {
-                new_arg = new_arg.replace(&format!("${{{}}}", key), val);
-                new_arg = new_arg.replace(&format!("${}", key), val);
-            }
-            new_arg
-        }).collect();
+        let args = expand_vars(raw_args, &vars);
 
         match cmd.as_str() {
-            "set" => {
-                 if args.len() >= 2 {
-                     vars.insert(args[0].clone(), args[1].clone());
-                 }
+            "set" if args.len() >= 2 => {
+                vars.insert(args[0].clone(), args[1].clone());
+            },
+            "set_prop_sources" => {
+                if args.is_empty() {
+                    ui.ui_print("Error: set_prop_sources requires <getprop|cmdline|file>...")?;
+                    continue;
+                }
+                set_prop_sources(args.iter().map(|a| PropSource::parse(a)).collect());
+                let slot_suffix = get_active_slot_suffix().unwrap_or_default();
+                vars.insert("INACTIVE_SLOT".to_string(), inactive_slot_suffix(&slot_suffix));
+                vars.insert("SLOT".to_string(), slot_suffix);
             },
             "ui_print" => {
-                let msg = args.get(0).cloned().unwrap_or_default();
+                let msg = args.first().cloned().unwrap_or_default();
                 ui.ui_print(&msg)?;
             },
             "show_progress" => {
-                let fraction = args.get(0).cloned().unwrap_or_else(|| "0.0".to_string());
+                let fraction = args.first().cloned().unwrap_or_else(|| "0.0".to_string());
                 let seconds = args.get(1).cloned().unwrap_or_else(|| "0".to_string());
                 ui.show_progress(&fraction, &seconds)?;
             },
             "verify_device" => {
-                let devices = args.get(0).context("verify_device missing args")?;
+                let devices = args.first().context("verify_device missing args")?;
                 verify_device(&mut ui, devices)?;
             },
+            "verify_fingerprint" => {
+                props::verify_fingerprint(&mut ui, &args)?;
+            },
+            "verify_build_newer" => {
+                let date = args.first().context("verify_build_newer missing args")?;
+                props::verify_build_newer(&mut ui, date)?;
+            },
+            "verify_prop" => {
+                if args.len() < 3 {
+                    ui.ui_print("Error: verify_prop requires <name> <op> <value>")?;
+                    continue;
+                }
+                props::verify_prop(&mut ui, &args[0], &args[1], &args[2])?;
+            },
+            "verify_firmware" => {
+                let passed = props::verify_firmware(&mut ui, &mut archive, &args)?;
+                if let Some(var) = args.iter().find_map(|a| a.strip_prefix("--set=")) {
+                    vars.insert(var.to_string(), if passed { "1" } else { "0" }.to_string());
+                }
+            },
             "verify_md5sum" => {
                 if args.len() < 2 { 
                     ui.ui_print("Error: verify_md5sum requires <file> <md5_file>")?;
@@ -101,16 +157,79 @@ fn main() -> Result<()> {
                 }
                 crate::func::verify_md5sum(&mut ui, &mut archive, &args[0], &args[1])?;
             },
+            "verify_sha1sum" => {
+                if args.len() < 2 { 
+                    ui.ui_print("Error: verify_sha1sum requires <file> <sha1_file>")?;
+                    continue; 
+                }
+                crate::func::verify_sha1sum(&mut ui, &mut archive, &args[0], &args[1])?;
+            },
+            "verify_sha256" | "verify_sha256sum" => {
+                if args.len() < 2 { 
+                    ui.ui_print("Error: verify_sha256 requires <file> <sha256_file>")?;
+                    continue; 
+                }
+                crate::func::verify_sha256sum(&mut ui, &mut archive, &args[0], &args[1])?;
+            },
+            "verify_manifest" => {
+                let manifest = args.first().context("verify_manifest missing args")?;
+                crate::func::verify_manifest(&mut ui, &mut archive, zip_path, manifest)?;
+            },
+            "verify_avb" => {
+                let vbmeta = args.first().context("verify_avb missing args")?;
+                avb::verify_avb(&mut ui, &mut archive, vbmeta, &args[1..])?;
+            },
+            "verify_signature" => {
+                let pubkey = args.first().context("verify_signature missing args")?;
+                signature::verify_signature(&mut ui, &mut archive, zip_path, pubkey)?;
+            },
             "package_extract_file" => {
                 if args.len() < 2 { continue; }
-                package_extract_file(&mut archive, &args[0], &args[1])?;
+                package_extract_file_cmd(&mut archive, &args)?;
             },
             "package_extract_targz" => {
                 if args.len() < 2 { continue; }
                 package_extract_targz(&mut archive, &args[0], &args[1])?;
             },
             "package_flash_partition" => {
-                package_flash_partition(&mut ui, &mut archive, &args)?;
+                package_flash_partition(&mut ui, &mut archive, zip_path, &args)?;
+            },
+            "parallel" => {
+                let mut jobs = Vec::new();
+                loop {
+                    let next = lines.next().context("parallel block is missing 'end'")??;
+                    let next = next.trim();
+                    if next.is_empty() || next.starts_with('#') { continue; }
+
+                    let job_parts = shell_words::split(next)
+                        .map_err(|_| anyhow::anyhow!("Syntax error in line: {}", next))?;
+                    if job_parts.is_empty() { continue; }
+                    if job_parts[0] == "end" { break; }
+                    if !parallel::is_parallel_command(&job_parts[0]) {
+                        bail!("{} can't be used in a parallel block", job_parts[0]);
+                    }
+                    jobs.push(parallel::Job {
+                        cmd: job_parts[0].clone(),
+                        args: expand_vars(&job_parts[1..], &vars),
+                    });
+                }
+                parallel::check_destinations(&jobs)?;
+                parallel::run_parallel(&mut ui, zip_path, jobs)?;
+            },
+            "backup_partition" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: backup_partition requires <block_device> <dest_file> [zstd]")?;
+                    continue;
+                }
+                let compress = args.get(2).is_some_and(|a| a == "zstd");
+                backup::backup_partition(&mut ui, &args[0], &args[1], compress)?;
+            },
+            "restore_partition" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: restore_partition requires <src_file> <block_device>")?;
+                    continue;
+                }
+                backup::restore_partition(&mut ui, &args[0], &args[1])?;
             },
             "update_dynamic_partitions" => {
                 if args.is_empty() { continue; }
@@ -125,11 +244,118 @@ fn main() -> Result<()> {
                 }
             },
             "disable_vbmeta" => {
-                crate::func::disable_vbmeta(&mut ui, &mut archive)?;
+                avb::disable_vbmeta(&mut ui)?;
+            },
+            "patch_vbmeta_flags" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: patch_vbmeta_flags requires <image> <flags>")?;
+                    continue;
+                }
+                avb::patch_vbmeta_flags(&mut ui, &args[0], &args[1])?;
+            },
+            "boot_image_unpack" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: boot_image_unpack requires <image> <dest_dir>")?;
+                    continue;
+                }
+                bootimg::boot_image_unpack(&mut ui, &args[0], &args[1])?;
+            },
+            "boot_image_repack" => {
+                bootimg::boot_image_repack(&mut ui, &mut archive, &args)?;
+            },
+            "ramdisk_add" => {
+                ramdisk::ramdisk_add(&mut ui, &mut archive, &args)?;
+            },
+            "ramdisk_remove" => {
+                ramdisk::ramdisk_remove(&mut ui, &args)?;
+            },
+            "ramdisk_extract" => {
+                ramdisk::ramdisk_extract(&mut ui, &args)?;
+            },
+            "dtbo_list" => {
+                let source = args.first().context("dtbo_list missing args")?;
+                dtbo::dtbo_list(&mut ui, &mut archive, source)?;
+            },
+            "dtbo_build" => {
+                dtbo::dtbo_build(&mut ui, &mut archive, &args)?;
             },
             "set_slot" => {
-                let slot = args.get(0).cloned().unwrap_or_else(|| "0".to_string());
-                set_slot(&mut ui, &slot)?;
+                let slot = args.first().cloned().unwrap_or_else(|| "0".to_string());
+                bootctrl::set_slot(&mut ui, &slot)?;
+            },
+            "mark_slot_successful" => {
+                bootctrl::mark_slot_successful(&mut ui, args.first().map(String::as_str))?;
+            },
+            "get_slot_info" => {
+                bootctrl::get_slot_info(&mut ui)?;
+            },
+            "copy_partition_slots" => {
+                slot_copy::copy_partition_slots(&mut ui, &args)?;
+            },
+            "mount" => {
+                mounts.mount(&mut ui, &args)?;
+            },
+            "unmount" => {
+                let mountpoint = args.first().context("unmount missing args")?;
+                mounts.unmount(&mut ui, mountpoint)?;
+            },
+            "is_mounted" => {
+                let mountpoint = args.first().context("is_mounted missing args")?;
+                let mounted = mount::is_mounted(mountpoint);
+                ui.ui_print(&format!("{}: {}", mountpoint, if mounted { "mounted" } else { "not mounted" }))?;
+                if let Some(var) = args.iter().find_map(|a| a.strip_prefix("--set=")) {
+                    vars.insert(var.to_string(), if mounted { "1" } else { "0" }.to_string());
+                }
+            },
+            "format" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: format requires <partition> <ext4|f2fs|erofs>")?;
+                    continue;
+                }
+                mount::format(&mut ui, &args[0], &args[1])?;
+            },
+            "prompt" => {
+                if args.len() < 2 {
+                    ui.ui_print("Error: prompt requires <var> <question>")?;
+                    continue;
+                }
+                let timeout = args.iter()
+                    .find_map(|a| a.strip_prefix("--timeout="))
+                    .map(|t| t.parse::<u64>().context(format!("Invalid prompt timeout: {}", t)))
+                    .transpose()?
+                    .unwrap_or(30);
+                let answer = ui.prompt(&args[1], Duration::from_secs(timeout))?;
+                if answer.is_none() {
+                    ui.ui_print("No answer, assuming no")?;
+                }
+                vars.insert(args[0].clone(), if answer == Some(true) { "1" } else { "0" }.to_string());
+            },
+            "wipe_data" => {
+                wipe::wipe_data(&mut ui, &mut mounts, &args, &vars)?;
+            },
+            "format_data" => {
+                wipe::format_data(&mut ui, &mut mounts, &args, &vars)?;
+            },
+            "delete" => {
+                fileops::delete(&mut ui, &args)?;
+            },
+            "delete_recursive" => {
+                fileops::delete_recursive(&mut ui, &args)?;
+            },
+            "symlink" => {
+                fileops::symlink(&mut ui, &args)?;
+            },
+            "set_perm" => {
+                fileops::set_perm(&args)?;
+            },
+            "set_perm_recursive" => {
+                fileops::set_perm_recursive(&mut ui, &args)?;
+            },
+            "set_metadata" => {
+                fileops::set_metadata(&args)?;
+            },
+            "set_metadata_recursive" => {
+                fileops::set_metadata_recursive(&mut ui, &args)?;
             },
             "run_program" => {
                 crate::func::run_program(&mut ui, &args)?;
@@ -141,4 +367,15 @@ fn main() -> Result<()> {
     let _ = fs::remove_file(script_path);
     let _ = fs::remove_dir_all("/tmp/lptools"); 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn expand_vars(raw_args: &[String], vars: &HashMap<String, String>) -> Vec<String> {
+    raw_args.iter().map(|arg| {
+        let mut new_arg = arg.to_string();
+        for (key, val) in vars {
+            new_arg = new_arg.replace(&format!("${{{}}}", key), val);
+            new_arg = new_arg.replace(&format!("${}", key), val);
+        }
+        new_arg
+    }).collect()
+}
@@ -0,0 +1,271 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::Command;
+use anyhow::{Context, Result, bail};
+
+use crate::recovery::RecoveryUI;
+use crate::func::{get_active_slot_suffix, slot_partition_path};
+
+/// Filesystems `mount` can detect and `format` can create.
+const FILESYSTEMS: [&str; 3] = ["ext4", "f2fs", "erofs"];
+
+/// Directories searched for mke2fs/make_f2fs/mkfs.erofs besides `$PATH`.
+const TOOL_DIRS: [&str; 4] = ["/tmp/lptools", "/sbin", "/system/bin", "/vendor/bin"];
+
+/// Resolves a partition to its block device: an absolute path is used as is,
+/// a name is looked up in `/dev/block/mapper` (dynamic partitions) and then
+/// `/dev/block/by-name`, with the current slot suffix first.
+pub fn resolve_block_device(partition: &str) -> Result<String> {
+    if partition.starts_with('/') {
+        return Ok(partition.to_string());
+    }
+    let suffix = get_active_slot_suffix().unwrap_or_default();
+    [
+        format!("/dev/block/mapper/{}{}", partition, suffix),
+        format!("/dev/block/mapper/{}", partition),
+        slot_partition_path(partition, &suffix),
+        slot_partition_path(partition, ""),
+    ]
+    .into_iter()
+    .find(|p| Path::new(p).exists())
+    .context(format!("Partition {} not found in /dev/block/mapper or /dev/block/by-name", partition))
+}
+
+/// Detects ext4, f2fs or erofs from the superblock.
+pub fn detect_fs(device: &str) -> Option<&'static str> {
+    let mut file = File::open(device).ok()?;
+    let mut sb = [0u8; 1024];
+    file.seek(SeekFrom::Start(1024)).ok()?;
+    file.read_exact(&mut sb).ok()?;
+    let le_u32 = |at: usize| u32::from_le_bytes(sb[at..at + 4].try_into().unwrap());
+    if u16::from_le_bytes([sb[56], sb[57]]) == 0xef53 {
+        Some("ext4")
+    } else if le_u32(0) == 0xf2f52010 {
+        Some("f2fs")
+    } else if le_u32(0) == 0xe0f5e1e2 {
+        Some("erofs")
+    } else {
+        None
+    }
+}
+
+/// Splits `ro,noatime,discard` into mount flags and the fs-specific data.
+fn parse_options(options: &str) -> (libc::c_ulong, String) {
+    let mut flags = 0;
+    let mut data = Vec::new();
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        flags |= match option {
+            "ro" => libc::MS_RDONLY,
+            "rw" | "defaults" => 0,
+            "nosuid" => libc::MS_NOSUID,
+            "nodev" => libc::MS_NODEV,
+            "noexec" => libc::MS_NOEXEC,
+            "sync" => libc::MS_SYNCHRONOUS,
+            "noatime" => libc::MS_NOATIME,
+            "nodiratime" => libc::MS_NODIRATIME,
+            "relatime" => libc::MS_RELATIME,
+            "remount" => libc::MS_REMOUNT,
+            "bind" => libc::MS_BIND,
+            other => {
+                data.push(other);
+                0
+            },
+        };
+    }
+    (flags, data.join(","))
+}
+
+fn sys_mount(device: &str, mountpoint: &str, fstype: &str, flags: libc::c_ulong, data: &str) -> io::Result<()> {
+    let c = |s: &str| CString::new(s).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput));
+    let (device, mountpoint, fstype, data) = (c(device)?, c(mountpoint)?, c(fstype)?, c(data)?);
+    let ret = unsafe {
+        libc::mount(device.as_ptr(), mountpoint.as_ptr(), fstype.as_ptr(), flags, data.as_ptr().cast())
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+fn sys_umount(mountpoint: &str, flags: libc::c_int) -> io::Result<()> {
+    let path = CString::new(mountpoint).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Mount points from `/proc/mounts` with their devices.
+fn proc_mounts() -> Vec<(String, String)> {
+    fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mountpoint = fields.next()?.replace("\\040", " ");
+            Some((device.to_string(), mountpoint))
+        })
+        .collect()
+}
+
+fn normalize(path: &str) -> String {
+    fs::canonicalize(path).map(|p| p.to_string_lossy().to_string()).unwrap_or_else(|_| path.trim_end_matches('/').to_string())
+}
+
+pub fn is_mounted(mountpoint: &str) -> bool {
+    let target = normalize(mountpoint);
+    proc_mounts().iter().any(|(_, m)| *m == target)
+}
+
+/// Where `device` is mounted, if anywhere.
+pub fn mount_point_of(device: &str) -> Option<String> {
+    let device = normalize(device);
+    proc_mounts().into_iter().find(|(d, _)| normalize(d) == device).map(|(_, m)| m)
+}
+
+/// Device mounted on `mountpoint`, if any.
+pub fn device_of(mountpoint: &str) -> Option<String> {
+    let target = normalize(mountpoint);
+    proc_mounts().into_iter().find(|(_, m)| *m == target).map(|(d, _)| d)
+}
+
+/// Finds the first of `names` in `$PATH` or the usual recovery tool dirs.
+fn find_tool(names: &[&str]) -> Option<String> {
+    let path = std::env::var("PATH").unwrap_or_default();
+    path.split(':')
+        .chain(TOOL_DIRS)
+        .flat_map(|dir| names.iter().map(move |name| format!("{}/{}", dir, name)))
+        .find(|p| Path::new(p).is_file())
+}
+
+/// Mounts done by the script. Whatever is still mounted when the script ends
+/// (or aborts) is unmounted in reverse order.
+#[derive(Default)]
+pub struct Mounts {
+    mounted: Vec<String>,
+}
+
+impl Mounts {
+    /// `mount <partition|mapper name|device> <mountpoint> [fstype] [options]`
+    pub fn mount(&mut self, ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+        if args.len() < 2 { bail!("mount requires <partition> <mountpoint>"); }
+        let mountpoint = args[1].trim_end_matches('/').to_string();
+        if is_mounted(&mountpoint) {
+            ui.ui_print(&format!("{} is already mounted", mountpoint))?;
+            return Ok(());
+        }
+
+        let device = resolve_block_device(&args[0])?;
+        let fstype = match args.get(2).map(String::as_str).filter(|t| !t.is_empty() && *t != "auto") {
+            Some(fstype) => fstype.to_string(),
+            None => detect_fs(&device).context(format!("Unknown filesystem on {}, pass the fstype", device))?.to_string(),
+        };
+        let (mut flags, data) = parse_options(args.get(3).map(String::as_str).unwrap_or(""));
+        if fstype == "erofs" {
+            flags |= libc::MS_RDONLY;
+        }
+        fs::create_dir_all(&mountpoint).context(format!("Failed to create {}", mountpoint))?;
+
+        let result = match sys_mount(&device, &mountpoint, &fstype, flags, &data) {
+            // Logical partitions are usually mapped read-only.
+            Err(e) if e.raw_os_error() == Some(libc::EROFS) && flags & libc::MS_RDONLY == 0 => {
+                ui.ui_print(&format!("Warning: {} is read-only, mounting read-only", device))?;
+                sys_mount(&device, &mountpoint, &fstype, flags | libc::MS_RDONLY, &data)
+            },
+            other => other,
+        };
+        if let Err(e) = result {
+            let msg = format!("Failed to mount {} ({}) on {}: {}", device, fstype, mountpoint, e);
+            let _ = ui.ui_print(&msg);
+            bail!("{}", msg);
+        }
+
+        ui.ui_print(&format!("Mounted {} on {} ({})", device, mountpoint, fstype))?;
+        self.mounted.push(mountpoint);
+        Ok(())
+    }
+
+    /// `unmount <mountpoint>`
+    pub fn unmount(&mut self, ui: &mut RecoveryUI, mountpoint: &str) -> Result<()> {
+        let mountpoint = mountpoint.trim_end_matches('/');
+        if !is_mounted(mountpoint) {
+            ui.ui_print(&format!("{} is not mounted", mountpoint))?;
+        } else if let Err(e) = sys_umount(mountpoint, 0) {
+            if e.raw_os_error() != Some(libc::EBUSY) {
+                bail!("Failed to unmount {}: {}", mountpoint, e);
+            }
+            ui.ui_print(&format!("Warning: {} is busy, detaching it", mountpoint))?;
+            sys_umount(mountpoint, libc::MNT_DETACH).context(format!("Failed to unmount {}", mountpoint))?;
+        }
+        self.mounted.retain(|m| m != mountpoint);
+        Ok(())
+    }
+}
+
+impl Drop for Mounts {
+    fn drop(&mut self) {
+        for mountpoint in self.mounted.iter().rev() {
+            if sys_umount(mountpoint, 0).is_err() {
+                let _ = sys_umount(mountpoint, libc::MNT_DETACH);
+            }
+        }
+    }
+}
+
+/// `format <partition> <ext4|f2fs|erofs>`: creates an empty filesystem with
+/// mke2fs, make_f2fs or mkfs.erofs, whichever the recovery ships.
+pub fn format(ui: &mut RecoveryUI, partition: &str, fstype: &str) -> Result<()> {
+    let device = resolve_block_device(partition)?;
+    format_device(ui, &device, fstype, false)
+}
+
+/// Formats `device`, with the `encrypt` feature fscrypt needs if `encryption`
+/// is set (userdata).
+pub fn format_device(ui: &mut RecoveryUI, device: &str, fstype: &str, encryption: bool) -> Result<()> {
+    if !FILESYSTEMS.contains(&fstype) {
+        bail!("Unsupported filesystem: {} (expected ext4, f2fs or erofs)", fstype);
+    }
+    if let Some(mountpoint) = mount_point_of(device) {
+        let msg = format!("{} is mounted on {}, unmount it first. Aborting...", device, mountpoint);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+
+    let mut args: Vec<String> = Vec::new();
+    let tools: &[&str] = match fstype {
+        "ext4" => {
+            args.extend(["-t", "ext4", "-b", "4096", "-F"].map(String::from));
+            if encryption { args.extend(["-O", "encrypt"].map(String::from)); }
+            args.push(device.to_string());
+            &["mke2fs", "mkfs.ext4"]
+        },
+        "f2fs" => {
+            args.extend(["-f", "-g", "android"].map(String::from));
+            if encryption { args.extend(["-O", "encrypt"].map(String::from)); }
+            args.push(device.to_string());
+            &["make_f2fs", "mkfs.f2fs"]
+        },
+        _ => {
+            let empty = "/tmp/erofs_empty";
+            fs::create_dir_all(empty)?;
+            args.extend([device.to_string(), empty.to_string()]);
+            &["mkfs.erofs"]
+        },
+    };
+    let Some(tool) = find_tool(tools) else {
+        let msg = format!("{} not found, can't format {} as {}. Aborting...", tools.join("/"), device, fstype);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    };
+
+    ui.ui_print(&format!("Formatting {} as {}...", device, fstype))?;
+    let output = Command::new(&tool).args(&args).output().context(format!("Failed to exec {}", tool))?;
+    if !output.status.success() {
+        let msg = format!(
+            "{} failed on {}: {}",
+            tool,
+            device,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use anyhow::{Context, Result, bail};
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::func::{package_extract_file_cmd, package_flash_partition, write_targets};
+
+/// One command inside a `parallel ... end` block.
+pub struct Job {
+    pub cmd: String,
+    pub args: Vec<String>,
+}
+
+thread_local! {
+    /// Raised when a job of the parallel block this thread works for fails.
+    static ABORT: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// Why a job stopped early: another job of its block failed.
+#[derive(Debug)]
+struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("stopped because another parallel job failed")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+/// Reader for zip entries. Inside a parallel job, reads fail with `Stopped`
+/// once another job of the block has failed; elsewhere it just passes through.
+pub struct Abortable<R>(pub R);
+
+impl<R: Read> Read for Abortable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if ABORT.with_borrow(|abort| abort.as_ref().is_some_and(|a| a.load(Ordering::Relaxed))) {
+            return Err(io::Error::other(Stopped));
+        }
+        self.0.read(buf)
+    }
+}
+
+fn is_stopped(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().and_then(|e| e.get_ref()).is_some_and(|inner| inner.is::<Stopped>())
+    })
+}
+
+pub fn is_parallel_command(cmd: &str) -> bool {
+    matches!(cmd, "package_flash_partition" | "package_extract_file")
+}
+
+/// Fails if two jobs of a block write to the same destination; they would
+/// race each other and the result would depend on which finishes last.
+pub fn check_destinations(jobs: &[Job]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for job in jobs {
+        for dest in write_targets(&job.cmd, &job.args)? {
+            if !seen.insert(dest.clone()) {
+                bail!("{} is written by more than one job of the parallel block", dest);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs every job on its own thread, each with its own `ZipArchive` handle and
+/// writers. Output is buffered per job and printed in script order. If any
+/// job fails, the others stop at their next read from the zip, all failures
+/// are reported and the block fails.
+pub fn run_parallel(ui: &mut RecoveryUI, zip_path: &str, jobs: Vec<Job>) -> Result<()> {
+    if jobs.is_empty() { return Ok(()); }
+
+    ui.ui_print(&format!("Running {} jobs in parallel...", jobs.len()))?;
+
+    let zip_path = zip_path.to_string();
+    let results = spawn_jobs(jobs, move |ui, job| run_job(ui, &zip_path, job));
+
+    let mut failures = 0;
+    for (label, output, result) in results {
+        ui.replay(&output)?;
+        match result {
+            Err(e) if is_stopped(&e) => ui.ui_print(&format!("- Stopped: {}", label))?,
+            Err(e) => {
+                failures += 1;
+                ui.ui_print(&format!("- Failed: {}: {:#}", label, e))?;
+            },
+            Ok(()) => {},
+        }
+    }
+
+    if failures > 0 {
+        let msg = format!("{} parallel job(s) failed. Aborting...", failures);
+        let _ = ui.ui_print(&msg);
+        bail!("{}", msg);
+    }
+    Ok(())
+}
+
+/// Runs `run` for every job on its own thread, with a buffered UI, and waits
+/// for all of them. The first failure raises the block's abort flag. Results
+/// are labeled and in job order.
+fn spawn_jobs<F>(jobs: Vec<Job>, run: F) -> Vec<(String, Vec<u8>, Result<()>)>
+where
+    F: Fn(&mut RecoveryUI, &Job) -> Result<()> + Send + Sync + 'static,
+{
+    let run = Arc::new(run);
+    let abort = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        let label = format!("{} {}", job.cmd, job.args.join(" "));
+        let abort = Arc::clone(&abort);
+        let run = Arc::clone(&run);
+        let handle = thread::spawn(move || -> (Vec<u8>, Result<()>) {
+            ABORT.set(Some(Arc::clone(&abort)));
+            let mut job_ui = RecoveryUI::buffered();
+            let result = run(&mut job_ui, &job);
+            if result.is_err() {
+                abort.store(true, Ordering::Relaxed);
+            }
+            (job_ui.take_buffer(), result)
+        });
+        handles.push((label, handle));
+    }
+
+    handles
+        .into_iter()
+        .map(|(label, handle)| match handle.join() {
+            Ok((output, result)) => (label, output, result),
+            Err(_) => (label, Vec::new(), Err(anyhow::anyhow!("job panicked"))),
+        })
+        .collect()
+}
+
+fn run_job(ui: &mut RecoveryUI, zip_path: &str, job: &Job) -> Result<()> {
+    let file = File::open(zip_path).context("Failed to open zip")?;
+    let mut archive = ZipArchive::new(file)?;
+
+    match job.cmd.as_str() {
+        "package_flash_partition" => {
+            package_flash_partition(ui, &mut archive, zip_path, &job.args)?;
+        },
+        "package_extract_file" => {
+            package_extract_file_cmd(&mut archive, &job.args)?;
+        },
+        other => bail!("{} can't be used in a parallel block", other),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(cmd: &str, args: &[&str]) -> Job {
+        Job { cmd: cmd.to_string(), args: args.iter().map(|s| s.to_string()).collect() }
+    }
+
+    #[test]
+    fn duplicate_destinations_are_rejected() {
+        let distinct = [
+            job("package_flash_partition", &["0", "boot.img.zst", "/dev/block/by-name/boot_a"]),
+            job("package_flash_partition", &["--direct", "2", "super.img", "0", "3", "/dev/block/by-name/super"]),
+            job("package_extract_file", &["vendor_boot.img", "/tmp/vendor_boot.img"]),
+        ];
+        assert!(check_destinations(&distinct).is_ok());
+
+        let twice = [
+            job("package_flash_partition", &["0", "boot.img.zst", "/dev/block/by-name/boot_a"]),
+            job("package_extract_file", &["boot.img", "/dev/block/by-name/boot_a"]),
+        ];
+        let err = check_destinations(&twice).unwrap_err();
+        assert!(err.to_string().contains("/dev/block/by-name/boot_a"), "{}", err);
+    }
+
+    #[test]
+    fn first_failure_stops_the_other_jobs() {
+        let jobs = vec![job("package_extract_file", &["missing.img", "/tmp/x"]), job("package_flash_partition", &["endless"])];
+        let results = spawn_jobs(jobs, |_, job| {
+            if job.cmd == "package_extract_file" {
+                bail!("File not found in zip");
+            }
+            // Only returns once the abort flag of the block is raised.
+            io::copy(&mut Abortable(io::repeat(0)), &mut io::sink())?;
+            Ok(())
+        });
+
+        let (label, _, failed) = &results[0];
+        assert_eq!(label, "package_extract_file missing.img /tmp/x");
+        assert!(failed.as_ref().is_err_and(|e| !is_stopped(e)));
+        let (_, _, stopped) = &results[1];
+        assert!(stopped.as_ref().is_err_and(is_stopped));
+    }
+}
@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::bootimg::read_source;
+use crate::func::{get_active_slot_suffix, getprop, slot_partition_path};
+
+const FINGERPRINT_PROPS: [&str; 3] = [
+    "ro.build.fingerprint",
+    "ro.system.build.fingerprint",
+    "ro.vendor.build.fingerprint",
+];
+
+/// Marker Qualcomm puts in front of the version string of modem, abl, tz...
+const QC_VERSION_MARKER: &[u8] = b"QC_IMAGE_VERSION_STRING=";
+
+/// Compares dotted versions component by component: numeric parts as
+/// numbers, anything else as text. `1.10` > `1.9`, `OS2.0.5` > `OS1.0.12`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |v: &str| -> Vec<String> {
+        let mut parts = Vec::new();
+        let mut current = String::new();
+        let mut digits = false;
+        for c in v.chars() {
+            if !c.is_ascii_alphanumeric() {
+                if !current.is_empty() { parts.push(std::mem::take(&mut current)); }
+                continue;
+            }
+            if !current.is_empty() && c.is_ascii_digit() != digits {
+                parts.push(std::mem::take(&mut current));
+            }
+            digits = c.is_ascii_digit();
+            current.push(c);
+        }
+        if !current.is_empty() { parts.push(current); }
+        parts
+    };
+
+    let (a, b) = (split(a), split(b));
+    for (x, y) in a.iter().zip(&b) {
+        let order = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.to_lowercase().cmp(&y.to_lowercase()),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Evaluates `actual <op> expected`. Plain comparisons are numeric,
+/// `version` comparisons use `compare_versions`.
+pub fn compare(actual: &str, op: &str, expected: &str) -> Result<bool> {
+    let numeric = |s: &str| -> Result<f64> {
+        s.trim().parse().context(format!("'{}' is not a number", s))
+    };
+    let ordering = |ord: Ordering, op: &str| match op {
+        ">" => ord == Ordering::Greater,
+        ">=" => ord != Ordering::Less,
+        "<" => ord == Ordering::Less,
+        "<=" => ord != Ordering::Greater,
+        _ => ord == Ordering::Equal,
+    };
+
+    Ok(match op {
+        "==" | "eq" => actual == expected,
+        "!=" | "ne" => actual != expected,
+        "=~" | "regex" => Regex::new(expected).context(format!("Invalid regex: {}", expected))?.is_match(actual),
+        "!~" => !Regex::new(expected).context(format!("Invalid regex: {}", expected))?.is_match(actual),
+        ">" | ">=" | "<" | "<=" => {
+            let ord = numeric(actual)?.partial_cmp(&numeric(expected)?).unwrap_or(Ordering::Equal);
+            ordering(ord, op)
+        },
+        _ => match op.strip_prefix("version") {
+            Some(v_op @ (">" | ">=" | "<" | "<=" | "==")) => ordering(compare_versions(actual, expected), v_op),
+            _ => bail!("Unknown operator: {} (expected ==, !=, =~, !~, >, >=, <, <=, version>=, ...)", op),
+        },
+    })
+}
+
+fn abort(ui: &mut RecoveryUI, msg: String) -> Result<()> {
+    let _ = ui.ui_print(&msg);
+    bail!("{}", msg);
+}
+
+/// `verify_prop <name> <op> <value>`
+pub fn verify_prop(ui: &mut RecoveryUI, name: &str, op: &str, expected: &str) -> Result<()> {
+    let actual = getprop(name);
+    let ok = match compare(&actual, op, expected) {
+        Ok(ok) => ok,
+        Err(e) => return abort(ui, format!("Property check on {} failed: {:#}. Aborting...", name, e)),
+    };
+    if !ok {
+        return abort(ui, format!(
+            "Property check failed: {} is '{}', expected {} '{}'. Aborting...",
+            name, actual, op, expected
+        ));
+    }
+    Ok(())
+}
+
+/// `verify_fingerprint <fingerprint...>`: the device's build fingerprint must
+/// be one of the given ones.
+pub fn verify_fingerprint(ui: &mut RecoveryUI, allowed: &[String]) -> Result<()> {
+    if allowed.is_empty() { bail!("verify_fingerprint requires at least one fingerprint"); }
+
+    let actual = FINGERPRINT_PROPS.iter().map(|p| getprop(p)).find(|v| !v.is_empty()).unwrap_or_default();
+    if !allowed.contains(&actual) {
+        let _ = ui.ui_print(&format!("Fingerprint is '{}', expected one of:", actual));
+        for fp in allowed {
+            let _ = ui.ui_print(&format!("- {}", fp));
+        }
+        return abort(ui, "This ROM requires a different base firmware. Aborting...".to_string());
+    }
+    Ok(())
+}
+
+/// `verify_build_newer <ro.build.date.utc>`: aborts if the installed build is
+/// newer than this zip (anti-downgrade).
+pub fn verify_build_newer(ui: &mut RecoveryUI, zip_date: &str) -> Result<()> {
+    let zip_date: u64 = zip_date.trim().parse().context(format!("Invalid build date: {}", zip_date))?;
+    let installed = getprop("ro.build.date.utc");
+    let Ok(installed_date) = installed.parse::<u64>() else {
+        ui.ui_print("Warning: ro.build.date.utc not found, skipping downgrade check")?;
+        return Ok(());
+    };
+
+    if installed_date > zip_date {
+        return abort(ui, format!(
+            "Downgrade blocked: installed build date is {}, this ROM is {}. Aborting...",
+            installed_date, zip_date
+        ));
+    }
+    Ok(())
+}
+
+/// Finds the `QC_IMAGE_VERSION_STRING=` embedded in a firmware partition
+/// (current slot first, then unslotted). Returns `None` if the partition
+/// doesn't exist or has no version string.
+fn firmware_image_version(partition: &str) -> Result<Option<String>> {
+    let suffix = get_active_slot_suffix().unwrap_or_default();
+    let Some(path) = [slot_partition_path(partition, &suffix), slot_partition_path(partition, "")]
+        .into_iter()
+        .find(|p| Path::new(p).exists())
+    else {
+        return Ok(None);
+    };
+
+    let mut reader = BufReader::new(File::open(&path).context(format!("Failed to open {}", path))?);
+    let mut chunk = vec![0u8; 1024 * 1024];
+    let mut window: Vec<u8> = Vec::new();
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        window.extend_from_slice(&chunk[..n]);
+        if let Some(start) = window.windows(QC_VERSION_MARKER.len()).position(|w| w == QC_VERSION_MARKER) {
+            let value = &window[start + QC_VERSION_MARKER.len()..];
+            if let Some(end) = value.iter().position(|&b| b == 0 || !b.is_ascii_graphic()) {
+                return Ok(Some(String::from_utf8_lossy(&value[..end]).to_string()));
+            }
+            // The string runs past this chunk, keep reading.
+            continue;
+        }
+        // Keep enough of the tail to catch a marker split across chunks.
+        let keep = window.len().min(QC_VERSION_MARKER.len() - 1);
+        window.drain(..window.len() - keep);
+    }
+}
+
+/// Current version of a firmware component: `bootloader` and `baseband`
+/// come from properties, anything else is read from the partition of that
+/// name (`modem`, `abl`, `tz`, ...). Empty if unknown.
+fn firmware_version(component: &str) -> Result<String> {
+    Ok(match component {
+        "bootloader" => getprop("ro.boot.bootloader"),
+        "baseband" => ["ro.baseband", "gsm.version.baseband"]
+            .iter()
+            .map(|p| getprop(p))
+            .find(|v| !v.is_empty())
+            .unwrap_or_default(),
+        partition => firmware_image_version(partition)?.unwrap_or_default(),
+    })
+}
+
+/// `verify_firmware <table> [--set=<var>]`: checks every `<component> <op> <value>`
+/// line of `<table>` (a ZIP entry or an absolute path). Without `--set` a
+/// mismatch aborts; with it, returns whether all checks passed so the caller
+/// can store it in `<var>`.
+pub fn verify_firmware(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, args: &[String]) -> Result<bool> {
+    let table = args.first().context("verify_firmware requires <table>")?;
+    let mut soft = false;
+    for arg in &args[1..] {
+        match arg.strip_prefix("--set=") {
+            Some(_) => soft = true,
+            None => bail!("Invalid verify_firmware option: {}", arg),
+        }
+    }
+
+    let data = read_source(archive, table)?;
+    let text = String::from_utf8(data).context(format!("{} is not a text file", table))?;
+
+    ui.ui_print("Checking firmware versions...")?;
+    let mut passed = true;
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let fields = shell_words::split(line).context(format!("Syntax error in {}: {}", table, line))?;
+        let [component, op, expected] = fields.as_slice() else {
+            bail!("Invalid line in {}, expected <component> <op> <value>: {}", table, line);
+        };
+
+        let actual = firmware_version(component)?;
+        let ok = !actual.is_empty() && compare(&actual, op, expected)?;
+        let shown = if actual.is_empty() { "unknown" } else { actual.as_str() };
+        if ok {
+            ui.ui_print(&format!("- {}: {} (ok)", component, shown))?;
+        } else {
+            ui.ui_print(&format!("- {}: {}, expected {} '{}'", component, shown, op, expected))?;
+            passed = false;
+        }
+    }
+
+    if !passed && !soft {
+        abort(ui, "This ROM requires a newer firmware. Flash the required firmware first. Aborting...".to_string())?;
+    }
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_ordering() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("14", "14.0"), Ordering::Less);
+        assert_eq!(compare_versions("V14.0.3.0.UMCMIXM", "v14.0.3.0.umcmixm"), Ordering::Equal);
+        assert_eq!(compare_versions("2023-12-05", "2024-01-01"), Ordering::Less);
+        assert_eq!(compare_versions("r12b", "r12a"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0rc2", "1.0rc10"), Ordering::Less);
+    }
+
+    #[test]
+    fn operators() {
+        assert!(compare("34", ">=", "33").unwrap());
+        assert!(!compare("9", ">", "10").unwrap());
+        assert!(compare("1.5", "<", "2").unwrap());
+        assert!(compare("lineage_19.1", "=~", "^lineage_").unwrap());
+        assert!(compare("OnePlus", "!~", "^Pixel").unwrap());
+        assert!(compare("ne", "!=", "eq").unwrap());
+        assert!(compare("2024-02-05", "version>=", "2024-01-01").unwrap());
+        assert!(compare("13.0.1", "version<", "13.1").unwrap());
+
+        assert!(compare("abc", ">", "1").is_err());
+        assert!(compare("1", "=~", "(").is_err());
+        assert!(compare("1", "~=", "1").is_err());
+        assert!(compare("1", "version!=", "1").is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use zip::ZipArchive;
+
+use crate::recovery::RecoveryUI;
+use crate::bootimg::{BootImage, read_source, resolve_image_path, write_image};
+
+const CPIO_MAGIC: &[u8; 6] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_LEGACY_MAGIC: u32 = 0x184c2102;
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Lz4Legacy,
+}
+
+impl Compression {
+    fn detect(data: &[u8]) -> Result<Self> {
+        if data.starts_with(&GZIP_MAGIC) {
+            Ok(Compression::Gzip)
+        } else if data.starts_with(&ZSTD_MAGIC) {
+            Ok(Compression::Zstd)
+        } else if data.starts_with(&LZ4_LEGACY_MAGIC.to_le_bytes()) {
+            Ok(Compression::Lz4Legacy)
+        } else if data.starts_with(CPIO_MAGIC) {
+            Ok(Compression::None)
+        } else {
+            bail!("unknown ramdisk compression");
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Compression::None => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lz4Legacy => "lz4-legacy",
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Compression::None => out.extend_from_slice(data),
+            Compression::Gzip => { flate2::read::MultiGzDecoder::new(data).read_to_end(&mut out)?; },
+            Compression::Zstd => { zstd::stream::Decoder::new(data)?.read_to_end(&mut out)?; },
+            Compression::Lz4Legacy => out = lz4_legacy_decompress(data)?,
+        }
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            },
+            Compression::Zstd => zstd::stream::encode_all(data, 19)?,
+            Compression::Lz4Legacy => lz4_legacy_compress(data),
+        })
+    }
+}
+
+/// LZ4 legacy frames: magic, then blocks of `<u32 compressed size><data>`,
+/// each up to 8 MiB uncompressed. Used by kernels for lz4 ramdisks.
+fn lz4_legacy_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 4;
+    while pos + 4 <= data.len() {
+        let size = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        pos += 4;
+        if size == LZ4_LEGACY_MAGIC { continue; }
+        if size == 0 { break; }
+        let block = data.get(pos..pos + size as usize).context("lz4 block is truncated")?;
+        out.extend_from_slice(
+            &lz4_flex::block::decompress(block, LZ4_LEGACY_BLOCK_SIZE).context("corrupt lz4 block")?,
+        );
+        pos += size as usize;
+    }
+    Ok(out)
+}
+
+fn lz4_legacy_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = LZ4_LEGACY_MAGIC.to_le_bytes().to_vec();
+    for chunk in data.chunks(LZ4_LEGACY_BLOCK_SIZE) {
+        let block = lz4_flex::block::compress(chunk);
+        out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+    out
+}
+
+pub struct CpioEntry {
+    pub name: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub data: Vec<u8>,
+}
+
+fn align4(value: usize) -> usize {
+    value.div_ceil(4) * 4
+}
+
+/// A newc cpio archive, as used by Android ramdisks.
+pub struct Cpio {
+    pub entries: Vec<CpioEntry>,
+}
+
+impl Cpio {
+    /// Parses one archive or several concatenated ones (zero padding in
+    /// between is skipped). Like the kernel, later entries replace earlier
+    /// ones with the same name.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut entries: Vec<CpioEntry> = Vec::new();
+        let mut pos = 0;
+        loop {
+            while data.get(pos) == Some(&0) {
+                pos += 1;
+            }
+            if pos >= data.len() { break; }
+
+            let header = data.get(pos..pos + CPIO_HEADER_SIZE).context("cpio archive is truncated")?;
+            if &header[..6] != CPIO_MAGIC {
+                bail!("bad cpio header at offset {} (only newc is supported)", pos);
+            }
+            let field = |i: usize| -> Result<u32> {
+                let hex = std::str::from_utf8(&header[6 + i * 8..14 + i * 8])?;
+                u32::from_str_radix(hex, 16).context("bad cpio header field")
+            };
+            let mode = field(1)?;
+            let file_size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_start = pos + CPIO_HEADER_SIZE;
+            let name = data.get(name_start..name_start + name_size).context("cpio archive is truncated")?;
+            let name = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(name)).into_owned();
+            let data_start = align4(name_start + name_size);
+            let file = data.get(data_start..data_start + file_size).context("cpio archive is truncated")?;
+            pos = align4(data_start + file_size);
+            if name == CPIO_TRAILER { continue; }
+
+            entries.retain(|e| e.name != name);
+            entries.push(CpioEntry {
+                name,
+                mode,
+                uid: field(2)?,
+                gid: field(3)?,
+                mtime: field(5)?,
+                rdev_major: field(9)?,
+                rdev_minor: field(10)?,
+                data: file.to_vec(),
+            });
+        }
+        Ok(Cpio { entries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries: Vec<&CpioEntry> = self.entries.iter().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut out = Vec::new();
+        let mut write = |ino: usize, name: &str, mode: u32, uid: u32, gid: u32, mtime: u32, rdev: (u32, u32), data: &[u8]| {
+            let fields = [
+                ino as u32, mode, uid, gid, 1, mtime, data.len() as u32,
+                0, 0, rdev.0, rdev.1, name.len() as u32 + 1, 0,
+            ];
+            out.extend_from_slice(CPIO_MAGIC);
+            for f in fields {
+                out.extend_from_slice(format!("{:08x}", f).as_bytes());
+            }
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.resize(align4(out.len()), 0);
+            out.extend_from_slice(data);
+            out.resize(align4(out.len()), 0);
+        };
+
+        for (i, e) in entries.iter().enumerate() {
+            write(300000 + i, &e.name, e.mode, e.uid, e.gid, e.mtime, (e.rdev_major, e.rdev_minor), &e.data);
+        }
+        write(0, CPIO_TRAILER, 0, 0, 0, 0, (0, 0), &[]);
+        out
+    }
+
+    fn find(&self, name: &str) -> Option<&CpioEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Adds or replaces a regular file, creating missing parent directories.
+    pub fn add_file(&mut self, name: &str, mode: u32, data: Vec<u8>) {
+        let mut parent = Path::new(name).parent();
+        while let Some(dir) = parent.and_then(|p| p.to_str()).filter(|p| !p.is_empty()) {
+            if self.find(dir).is_none() {
+                self.entries.push(CpioEntry {
+                    name: dir.to_string(), mode: S_IFDIR | 0o755, uid: 0, gid: 0, mtime: 0,
+                    rdev_major: 0, rdev_minor: 0, data: Vec::new(),
+                });
+            }
+            parent = Path::new(dir).parent();
+        }
+
+        self.entries.retain(|e| e.name != name);
+        self.entries.push(CpioEntry {
+            name: name.to_string(), mode: S_IFREG | (mode & 0o7777), uid: 0, gid: 0, mtime: 0,
+            rdev_major: 0, rdev_minor: 0, data,
+        });
+    }
+
+    /// Removes `name` and, for directories, everything under it. Returns how
+    /// many entries were removed.
+    pub fn remove(&mut self, name: &str) -> usize {
+        let prefix = format!("{}/", name);
+        let before = self.entries.len();
+        self.entries.retain(|e| e.name != name && !e.name.starts_with(&prefix));
+        before - self.entries.len()
+    }
+}
+
+/// The image, the ramdisk section name, the ramdisk's compression and its
+/// parsed contents.
+fn open_ramdisk(target: &str, fragment: Option<&str>) -> Result<(String, BootImage, String, Compression, Cpio)> {
+    let path = resolve_image_path(target);
+    let image = BootImage::load(&path)?;
+    let section = match fragment {
+        Some(name) => format!("ramdisk:{}", name),
+        None => "ramdisk".to_string(),
+    };
+    let raw = image.ramdisk(&section)?;
+    if raw.is_empty() {
+        bail!("{} has an empty ramdisk", path);
+    }
+    let compression = Compression::detect(raw)?;
+    let cpio = Cpio::parse(&compression.decompress(raw)?).context(format!("Failed to parse the ramdisk of {}", path))?;
+    Ok((path, image, section, compression, cpio))
+}
+
+fn save_ramdisk(ui: &mut RecoveryUI, path: &str, mut image: BootImage, section: &str, compression: Compression, cpio: &Cpio) -> Result<()> {
+    let data = compression.compress(&cpio.to_bytes())?;
+    image.replace(section, data)?;
+    write_image(ui, &image, path)
+}
+
+/// Pulls a `--fragment=<name>` option (vendor_boot v4 ramdisk fragment) out of `args`.
+fn split_fragment(args: &[String]) -> (Vec<String>, Option<String>) {
+    let mut rest = Vec::new();
+    let mut fragment = None;
+    for arg in args {
+        match arg.strip_prefix("--fragment=") {
+            Some(name) => fragment = Some(name.to_string()),
+            None => rest.push(arg.clone()),
+        }
+    }
+    (rest, fragment)
+}
+
+/// `ramdisk_add <image> <path> <src> [mode]`
+pub fn ramdisk_add(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, args: &[String]) -> Result<()> {
+    let (args, fragment) = split_fragment(args);
+    if args.len() < 3 { bail!("ramdisk_add requires <image> <path> <src> [mode]"); }
+    let mode = match args.get(3) {
+        Some(m) => u32::from_str_radix(m, 8).context(format!("Invalid mode: {}", m))?,
+        None => 0o644,
+    };
+
+    let (path, image, section, compression, mut cpio) = open_ramdisk(&args[0], fragment.as_deref())?;
+    let name = args[1].trim_start_matches('/');
+    let data = read_source(archive, &args[2])?;
+    ui.ui_print(&format!("Adding {} to the {} ramdisk of {}...", name, compression.label(), path))?;
+    cpio.add_file(name, mode, data);
+    save_ramdisk(ui, &path, image, &section, compression, &cpio)
+}
+
+/// `ramdisk_remove <image> <path>`
+pub fn ramdisk_remove(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    let (args, fragment) = split_fragment(args);
+    if args.len() < 2 { bail!("ramdisk_remove requires <image> <path>"); }
+
+    let (path, image, section, compression, mut cpio) = open_ramdisk(&args[0], fragment.as_deref())?;
+    let name = args[1].trim_start_matches('/');
+    let removed = cpio.remove(name);
+    if removed == 0 {
+        ui.ui_print(&format!("{} is not in the ramdisk of {}, nothing to remove", name, path))?;
+        return Ok(());
+    }
+    ui.ui_print(&format!("Removing {} ({} entries) from the ramdisk of {}...", name, removed, path))?;
+    save_ramdisk(ui, &path, image, &section, compression, &cpio)
+}
+
+/// `ramdisk_extract <image> <path> <dest_file>`
+pub fn ramdisk_extract(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    let (args, fragment) = split_fragment(args);
+    if args.len() < 3 { bail!("ramdisk_extract requires <image> <path> <dest_file>"); }
+
+    let (path, _, _, _, cpio) = open_ramdisk(&args[0], fragment.as_deref())?;
+    let name = args[1].trim_start_matches('/');
+    let entry = cpio.find(name).context(format!("{} is not in the ramdisk of {}", name, path))?;
+    if entry.mode & S_IFMT != S_IFREG {
+        bail!("{} is not a regular file", name);
+    }
+
+    if let Some(parent) = Path::new(&args[2]).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&args[2], &entry.data)?;
+    ui.ui_print(&format!("Extracted {} from {} to {}", name, path, args[2]))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(files: &[(&str, &[u8])]) -> Cpio {
+        let mut cpio = Cpio { entries: Vec::new() };
+        for (name, data) in files {
+            cpio.add_file(name, 0o644, data.to_vec());
+        }
+        cpio
+    }
+
+    fn names(cpio: &Cpio) -> Vec<&str> {
+        let mut names: Vec<&str> = cpio.entries.iter().map(|e| e.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpio = archive(&[("init", b"\x7fELF"), ("system/etc/init/a.rc", b"service a /a\n")]);
+        cpio.entries.push(CpioEntry {
+            name: "dev/console".to_string(), mode: 0o020600, uid: 0, gid: 0, mtime: 7,
+            rdev_major: 5, rdev_minor: 1, data: Vec::new(),
+        });
+        let bytes = cpio.to_bytes();
+        let parsed = Cpio::parse(&bytes).unwrap();
+
+        assert_eq!(names(&parsed), ["dev/console", "init", "system", "system/etc", "system/etc/init", "system/etc/init/a.rc"]);
+        let console = parsed.find("dev/console").unwrap();
+        assert_eq!((console.mode, console.mtime, console.rdev_major, console.rdev_minor), (0o020600, 7, 5, 1));
+        assert_eq!(parsed.find("system/etc/init/a.rc").unwrap().data, b"service a /a\n");
+        assert_eq!(parsed.find("system").unwrap().mode, S_IFDIR | 0o755);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn concatenated_archives() {
+        let mut bytes = archive(&[("init", b"first"), ("a", b"a")]).to_bytes();
+        bytes.resize(bytes.len().next_multiple_of(512), 0);
+        bytes.extend(archive(&[("init", b"second"), ("b", b"b")]).to_bytes());
+        bytes.extend([0; 100]);
+
+        let parsed = Cpio::parse(&bytes).unwrap();
+        assert_eq!(names(&parsed), ["a", "b", "init"]);
+        assert_eq!(parsed.find("init").unwrap().data, b"second");
+    }
+
+    #[test]
+    fn truncated_archive_is_an_error() {
+        let bytes = archive(&[("init", b"data")]).to_bytes();
+        assert!(Cpio::parse(&bytes[..CPIO_HEADER_SIZE + 4]).is_err());
+        assert!(Cpio::parse(b"garbage").is_err());
+    }
+}
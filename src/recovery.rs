@@ -1,32 +1,134 @@
-use std::fs::File;
-use std::io::Write;
-use std::os::unix::io::FromRawFd;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
 use anyhow::Result;
 
+const EV_KEY: u16 = 0x01;
+const KEY_VOLUMEDOWN: u16 = 114;
+const KEY_VOLUMEUP: u16 = 115;
+
+enum Output {
+    Pipe(File),
+    Buffer(Vec<u8>),
+}
+
+impl Output {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Output::Pipe(f) => f,
+            Output::Buffer(b) => b,
+        }
+    }
+}
+
 pub struct RecoveryUI {
-    pipe: File,
+    pipe: Output,
 }
 
 impl RecoveryUI {
     pub unsafe fn new(fd_num: i32) -> Result<Self> {
         let pipe = unsafe { File::from_raw_fd(fd_num) };
-        Ok(RecoveryUI { pipe })
+        Ok(RecoveryUI { pipe: Output::Pipe(pipe) })
+    }
+
+    /// A UI that records commands instead of sending them to recovery, so
+    /// output from background jobs can be replayed in order later.
+    pub fn buffered() -> Self {
+        RecoveryUI { pipe: Output::Buffer(Vec::new()) }
+    }
+
+    /// Takes everything recorded by a `buffered` UI.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        match &mut self.pipe {
+            Output::Buffer(b) => std::mem::take(b),
+            Output::Pipe(_) => Vec::new(),
+        }
+    }
+
+    /// Sends commands recorded by a `buffered` UI.
+    pub fn replay(&mut self, recorded: &[u8]) -> Result<()> {
+        let pipe = self.pipe.writer();
+        pipe.write_all(recorded)?;
+        pipe.flush()?;
+        Ok(())
     }
 
     pub fn ui_print(&mut self, message: &str) -> Result<()> {
-        writeln!(self.pipe, "ui_print {}", message)?;
-        writeln!(self.pipe, "ui_print")?;
-        self.pipe.flush()?;
+        let pipe = self.pipe.writer();
+        writeln!(pipe, "ui_print {}", message)?;
+        writeln!(pipe, "ui_print")?;
+        pipe.flush()?;
+        Ok(())
+    }
+
+    /// Sets how far the current `show_progress` segment is filled.
+    pub fn set_progress(&mut self, fraction: f32) -> Result<()> {
+        let pipe = self.pipe.writer();
+        writeln!(pipe, "set_progress {}", fraction.clamp(0.0, 1.0))?;
+        pipe.flush()?;
         Ok(())
     }
 
     pub fn show_progress(&mut self, fraction_str: &str, seconds_str: &str) -> Result<()> {
         let fraction: f32 = fraction_str.parse().unwrap_or(0.0);
         let seconds: i32 = seconds_str.parse().unwrap_or(0);
-    
-        writeln!(self.pipe, "progress {} {}", fraction, seconds)?;
-        self.pipe.flush()?;
-        
+
+        let pipe = self.pipe.writer();
+        writeln!(pipe, "progress {} {}", fraction, seconds)?;
+        pipe.flush()?;
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Asks a yes/no question answered with the volume keys (up = yes,
+    /// down = no). `None` if nothing was pressed before the timeout or no
+    /// input device could be opened.
+    pub fn prompt(&mut self, question: &str, timeout: Duration) -> Result<Option<bool>> {
+        let devices: Vec<File> = fs::read_dir("/dev/input")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with("event"))
+            .filter_map(|e| File::open(e.path()).ok())
+            .collect();
+        if devices.is_empty() {
+            return Ok(None);
+        }
+
+        self.ui_print(question)?;
+        self.ui_print("  Volume up = yes, volume down = no")?;
+        let mut fds: Vec<libc::pollfd> = devices
+            .iter()
+            .map(|f| libc::pollfd { fd: f.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+            .collect();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, left.as_millis() as libc::c_int) };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue; }
+                return Err(err.into());
+            }
+
+            for (pfd, mut device) in fds.iter().zip(&devices) {
+                if pfd.revents & libc::POLLIN == 0 { continue; }
+                let mut buf = [0u8; std::mem::size_of::<libc::input_event>()];
+                if device.read_exact(&mut buf).is_err() { continue; }
+                let event: libc::input_event = unsafe { std::ptr::read_unaligned(buf.as_ptr().cast()) };
+                // value 1 is the key press, 0 the release.
+                if event.type_ == EV_KEY && event.value == 1 {
+                    match event.code {
+                        KEY_VOLUMEUP => return Ok(Some(true)),
+                        KEY_VOLUMEDOWN => return Ok(Some(false)),
+                        _ => {},
+                    }
+                }
+            }
+        }
+    }
+}
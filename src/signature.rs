@@ -0,0 +1,148 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use anyhow::{Context, Result, bail};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::Sha256;
+use zip::ZipArchive;
+use zip::write::{FileOptions, ZipWriter};
+
+use crate::recovery::RecoveryUI;
+use crate::func::{from_hex, hash_reader, parse_manifest, to_hex, verify_manifest_entries};
+
+pub const SIGNED_MANIFEST: &str = "META-INF/RAPIDFLASHER.sha256";
+pub const SIGNED_MANIFEST_SIG: &str = "META-INF/RAPIDFLASHER.sha256.sig";
+
+/// Verifies the detached Ed25519 signature over the zip's signed manifest,
+/// checks that the manifest covers every entry in the zip, then verifies all
+/// entry hashes. Aborts if anything is missing, unsigned or tampered with.
+pub fn verify_signature(
+    ui: &mut RecoveryUI,
+    archive: &mut ZipArchive<File>,
+    zip_path: &str,
+    pubkey_entry: &str,
+) -> Result<()> {
+    ui.ui_print("Verifying zip signature...")?;
+
+    let pubkey_hex = read_entry_or_abort(ui, archive, pubkey_entry)?;
+    let manifest = read_entry_or_abort(ui, archive, SIGNED_MANIFEST)?;
+    let sig_hex = read_entry_or_abort(ui, archive, SIGNED_MANIFEST_SIG)?;
+
+    let pubkey = match parse_pubkey(&pubkey_hex) {
+        Ok(k) => k,
+        Err(e) => abort(ui, &format!("Signature check failed: invalid public key {} ({})", pubkey_entry, e))?,
+    };
+    ui.ui_print(&format!("  Key: {}", to_hex(pubkey.as_bytes())))?;
+
+    let signature = match from_hex(&sig_hex).ok().and_then(|b| Signature::from_slice(&b).ok()) {
+        Some(s) => s,
+        None => abort(ui, &format!("Signature check failed: {} is malformed", SIGNED_MANIFEST_SIG))?,
+    };
+
+    if pubkey.verify(manifest.as_bytes(), &signature).is_err() {
+        abort(ui, "Signature check failed: manifest signature is INVALID. ZIP was tampered with! Aborting...")?;
+    }
+
+    let entries = parse_manifest(SIGNED_MANIFEST, &manifest)?;
+    let mut unlisted = Vec::new();
+    for name in archive.file_names() {
+        if name.ends_with('/') || name == SIGNED_MANIFEST || name == SIGNED_MANIFEST_SIG { continue; }
+        if !entries.iter().any(|e| e.name == name) {
+            unlisted.push(name.to_string());
+        }
+    }
+    if !unlisted.is_empty() {
+        for name in &unlisted {
+            ui.ui_print(&format!("- {} is not covered by the signature", name))?;
+        }
+        abort(ui, &format!("Signature check failed: {} unsigned file(s) in ZIP. Aborting...", unlisted.len()))?;
+    }
+
+    verify_manifest_entries(ui, zip_path, SIGNED_MANIFEST, entries)?;
+    ui.ui_print("Signature OK")?;
+    Ok(())
+}
+
+/// Host-side: generates a new Ed25519 key pair. The secret key is written as
+/// hex to `secret_out`, the public key to `secret_out.pub`.
+pub fn keygen(secret_out: &str) -> Result<()> {
+    let mut seed = [0u8; 32];
+    File::open("/dev/urandom")
+        .context("Failed to open /dev/urandom")?
+        .read_exact(&mut seed)?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(secret_out)
+        .context(format!("Failed to create {}", secret_out))?;
+    writeln!(out, "{}", to_hex(&seed))?;
+
+    let pub_out = format!("{}.pub", secret_out);
+    fs::write(&pub_out, format!("{}\n", to_hex(key.verifying_key().as_bytes())))?;
+
+    println!("Secret key: {}", secret_out);
+    println!("Public key: {} ({})", pub_out, to_hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+/// Host-side: hashes every entry of `zip_path`, then appends the signed
+/// manifest and its detached signature to the zip.
+pub fn sign_zip(zip_path: &str, secret_key_path: &str) -> Result<()> {
+    let seed = from_hex(&fs::read_to_string(secret_key_path)?)
+        .context(format!("Invalid secret key in {}", secret_key_path))?;
+    let seed: [u8; 32] = seed
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Secret key must be 32 bytes"))?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let mut manifest = String::new();
+    {
+        let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let name = entry.name().to_string();
+            if name == SIGNED_MANIFEST || name == SIGNED_MANIFEST_SIG {
+                bail!("{} is already signed", zip_path);
+            }
+            if entry.is_dir() { continue; }
+            let hash = hash_reader::<Sha256, _>(&mut entry)?;
+            manifest.push_str(&format!("{}  {}\n", hash, name));
+        }
+    }
+
+    let signature = key.sign(manifest.as_bytes());
+
+    let file = OpenOptions::new().read(true).write(true).open(zip_path)?;
+    let mut zip = ZipWriter::new_append(file)?;
+    let options = FileOptions::default();
+    zip.start_file(SIGNED_MANIFEST, options)?;
+    zip.write_all(manifest.as_bytes())?;
+    zip.start_file(SIGNED_MANIFEST_SIG, options)?;
+    zip.write_all(format!("{}\n", to_hex(&signature.to_bytes())).as_bytes())?;
+    zip.finish()?;
+
+    println!("Signed {} with key {}", zip_path, to_hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn parse_pubkey(hex: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = from_hex(hex)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+fn read_entry_or_abort(ui: &mut RecoveryUI, archive: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut content = String::new();
+    match archive.by_name(name) {
+        Ok(mut entry) => { entry.read_to_string(&mut content)?; },
+        Err(_) => abort(ui, &format!("Signature check failed: {} is missing from the ZIP.", name))?,
+    }
+    Ok(content)
+}
+
+fn abort<T>(ui: &mut RecoveryUI, msg: &str) -> Result<T> {
+    let _ = ui.ui_print(msg);
+    bail!("{}", msg);
+}
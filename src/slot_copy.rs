@@ -0,0 +1,157 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use anyhow::{Context, Result, bail};
+
+use crate::recovery::RecoveryUI;
+use crate::blockdev::{HoleMode, device_size, ensure_fits};
+use crate::func::{get_active_slot_suffix, inactive_slot_suffix, slot_partition_path};
+use crate::sparse::write_hole;
+
+const COPY_BLOCK_SIZE: usize = 1024 * 1024;
+const COPY_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// `copy_partition_slots <name...> [--from=active|inactive]`: copies each
+/// partition from one slot to the other, so both slots carry the same
+/// firmware. All-zero blocks are zeroed with `BLKZEROOUT` instead of written.
+pub fn copy_partition_slots(ui: &mut RecoveryUI, args: &[String]) -> Result<()> {
+    let mut names = Vec::new();
+    let mut from_active = true;
+    for arg in args {
+        match arg.strip_prefix("--from=") {
+            Some("active") => from_active = true,
+            Some("inactive") => from_active = false,
+            Some(other) => bail!("Invalid --from value: {} (expected active or inactive)", other),
+            None => names.push(arg.as_str()),
+        }
+    }
+    if names.is_empty() { bail!("copy_partition_slots requires at least one partition name"); }
+
+    let active = get_active_slot_suffix().unwrap_or_default();
+    if active.is_empty() {
+        let msg = "copy_partition_slots needs an A/B device, but no slot suffix was found. Aborting...";
+        let _ = ui.ui_print(msg);
+        bail!("{}", msg);
+    }
+    let inactive = inactive_slot_suffix(&active);
+    let (from, to) = if from_active { (active, inactive) } else { (inactive, active) };
+
+    let mut pairs = Vec::with_capacity(names.len());
+    let mut total = 0u64;
+    for name in names {
+        let src = slot_partition_path(name, &from);
+        let dst = slot_partition_path(name, &to);
+        let size = match device_size(&src)? {
+            Some(size) => size,
+            None => std::fs::metadata(&src).context(format!("Failed to open {}", src))?.len(),
+        };
+        ensure_fits(ui, &src, size, &dst)?;
+        total += size;
+        pairs.push((src, dst, size));
+    }
+
+    let mut done = 0u64;
+    for (src, dst, size) in pairs {
+        ui.ui_print(&format!("Copying {} to {}...", src, dst))?;
+        let zeroed = copy_partition(ui, &src, &dst, size, &mut done, total)?;
+        ui.ui_print(&format!(
+            "  {} MiB copied, {} MiB of zeros skipped",
+            (size - zeroed) / (1024 * 1024),
+            zeroed / (1024 * 1024)
+        ))?;
+    }
+    Ok(())
+}
+
+/// Copies `size` bytes from `src` to `dst`, returning how many were zero
+/// blocks that got zeroed out instead of written.
+fn copy_partition(ui: &mut RecoveryUI, src: &str, dst: &str, size: u64, done: &mut u64, total: u64) -> Result<u64> {
+    let mut source = File::open(src).context(format!("Failed to open {}", src))?;
+    let dest = OpenOptions::new().write(true).create(true).truncate(false).open(dst)
+        .context(format!("Failed to open {}", dst))?;
+    let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, dest);
+
+    let mut buf = vec![0u8; COPY_BLOCK_SIZE];
+    let zeros = vec![0u8; COPY_BLOCK_SIZE];
+    let mut pos = 0u64;
+    let mut zero_start = None;
+    let mut zeroed = 0u64;
+
+    while pos < size {
+        let len = std::cmp::min(COPY_BLOCK_SIZE as u64, size - pos) as usize;
+        source.read_exact(&mut buf[..len]).context(format!("Failed to read {}", src))?;
+
+        if buf[..len].iter().all(|&b| b == 0) {
+            zero_start.get_or_insert(pos);
+        } else {
+            if let Some(start) = zero_start.take() {
+                write_hole(&mut writer, HoleMode::ZeroOut, start, pos - start, &zeros)?;
+                zeroed += pos - start;
+            }
+            writer.write_all(&buf[..len])?;
+        }
+
+        pos += len as u64;
+        let before = *done * 100 / total;
+        *done += len as u64;
+        if *done * 100 / total != before {
+            ui.set_progress(*done as f32 / total as f32)?;
+        }
+    }
+    if let Some(start) = zero_start {
+        write_hole(&mut writer, HoleMode::ZeroOut, start, pos - start, &zeros)?;
+        zeroed += pos - start;
+    }
+
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(zeroed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("rf-slot-copy-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn zero_blocks_become_holes() {
+        const MIB: usize = COPY_BLOCK_SIZE;
+        // data, zeros, zeros, data with a zero tail, a partial zero block.
+        let mut data = vec![0u8; 4 * MIB + 4096];
+        data[..MIB].fill(0x11);
+        data[3 * MIB..3 * MIB + 100].fill(0x22);
+        let (src, dst) = (temp_path("src"), temp_path("dst"));
+        std::fs::write(&src, &data).unwrap();
+        std::fs::write(&dst, vec![0xffu8; data.len() + 4096]).unwrap();
+
+        let mut ui = RecoveryUI::buffered();
+        let size = data.len() as u64;
+        let mut done = 0;
+        let zeroed = copy_partition(&mut ui, &src, &dst, size, &mut done, size).unwrap();
+        assert_eq!(zeroed, 2 * MIB as u64 + 4096);
+        assert_eq!(done, size);
+
+        // Only `size` bytes are copied, the rest of the destination stays.
+        let copied = std::fs::read(&dst).unwrap();
+        assert_eq!(copied[..data.len()], data[..]);
+        assert!(copied[data.len()..].iter().all(|&b| b == 0xff));
+        let output = String::from_utf8(ui.take_buffer()).unwrap();
+        assert!(output.ends_with("set_progress 1\n"), "{}", output);
+
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+    }
+
+    #[test]
+    fn short_source_is_an_error() {
+        let (src, dst) = (temp_path("short-src"), temp_path("short-dst"));
+        std::fs::write(&src, vec![1u8; 4096]).unwrap();
+        let mut done = 0;
+        let err = copy_partition(&mut RecoveryUI::buffered(), &src, &dst, 8192, &mut done, 8192).unwrap_err();
+        assert!(err.to_string().contains("Failed to read"), "{}", err);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+    }
+}
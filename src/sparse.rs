@@ -1,65 +1,89 @@
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write, ErrorKind};
 use byteorder::{LittleEndian, ReadBytesExt}; 
 use anyhow::{Context, Result, bail};
 use zip::ZipArchive;
 
+use crate::blockdev::{DiffStats, HoleMode, write_if_changed};
+use crate::func::FlashOptions;
+use crate::parallel::Abortable;
+
 const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
 const CHUNK_TYPE_RAW: u16 = 0xCAC1;
 const CHUNK_TYPE_FILL: u16 = 0xCAC2;
 const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
 const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+const CHUNK_HEADER_SIZE: u16 = 12;
 
 pub fn flash_sparse(
     ui: &mut crate::recovery::RecoveryUI,
     archive: &mut ZipArchive<File>,
-    args: &[String]
+    args: &[String],
+    opts: &FlashOptions,
 ) -> Result<()> {
     let raw_name = &args[1];
 
-    let (zip_base, start, end, partition_path, is_range) = if raw_name.ends_with(".*") {
-        let base = &raw_name[..raw_name.len() - 2];
+    let (entry_names, partition_path) = if let Some(base) = raw_name.strip_suffix(".*") {
         let partition = args.get(2).context("Missing partition arg")?;
 
         ui.ui_print(&format!("Auto-detecting '{}' chunks...", base))?;
 
-        let mut min_idx = usize::MAX;
-        let mut max_idx = 0;
-        let mut found_any = false;
-
-        let num_files = archive.len();
-        for i in 0..num_files {
-            let file = archive.by_index(i)?;
-            let name = file.name();
-            if name.starts_with(base) {
-                if let Some(suffix) = name.strip_prefix(base) {
-                    if suffix.starts_with('.') {
-                        if let Ok(idx) = suffix[1..].parse::<usize>() {
-                            if idx < min_idx { min_idx = idx; }
-                            if idx > max_idx { max_idx = idx; }
-                            found_any = true;
-                        }
-                    }
-                }
-            }
-        }
-
-        if !found_any { bail!("No chunks found for {}.*", base); }
-        ui.ui_print(&format!("  Found chunks {} to {}", min_idx, max_idx))?;
-        (base.to_string(), min_idx, max_idx, partition.to_string(), true)
+        let chunks = find_chunks(archive, base)?;
+        if chunks.is_empty() { bail!("No chunks found for {}.*", base); }
+        ui.ui_print(&format!("  Found chunks {} to {}", chunks[0].0, chunks[chunks.len() - 1].0))?;
+        (chunks.into_iter().map(|(_, name)| name).collect(), partition.to_string())
 
     } else {
         let arg2 = args.get(2).context("Missing arg2")?;
         if arg2.chars().all(|c| c.is_numeric()) {
-            let start = arg2.parse()?;
-            let end = args.get(3).context("Missing end")?.parse()?;
+            let start: usize = arg2.parse()?;
+            let end: usize = args.get(3).context("Missing end")?.parse()?;
             let partition = args.get(4).context("Missing partition")?;
-            (raw_name.to_string(), start, end, partition.to_string(), true)
+
+            let chunks = find_chunks(archive, raw_name)?;
+            let mut names = Vec::new();
+            for i in start..=end {
+                match chunks.iter().find(|(idx, _)| *idx == i) {
+                    Some((_, name)) => names.push(name.clone()),
+                    None => bail!("Chunk {}.{} not found", raw_name, i),
+                }
+            }
+            (names, partition.to_string())
         } else {
-            (raw_name.to_string(), 0, 0, arg2.to_string(), false)
+            (vec![raw_name.to_string()], arg2.to_string())
         }
     };
 
+    let mut infos = Vec::with_capacity(entry_names.len());
+    for name in &entry_names {
+        let mut entry = archive.by_name(name).context(format!("Chunk {} not found", name))?;
+        infos.push(read_sparse_info(&mut entry).context(format!("Invalid sparse image {}", name))?);
+    }
+
+    // Fastboot/Motorola sparsechunks all describe the full image and start
+    // with a DONT_CARE chunk that skips to their position, so each is written
+    // from offset 0. Other split tools produce chunks that each cover only
+    // their own part and must be written one after another.
+    let absolute = infos.iter().all(|i| i.size == infos[0].size)
+        && infos.iter().skip(1).all(|i| i.leading_skip);
+
+    let mut offsets = Vec::with_capacity(infos.len());
+    let mut image_size = 0;
+    for info in &infos {
+        if absolute {
+            offsets.push(0);
+            image_size = info.size;
+        } else {
+            offsets.push(image_size);
+            image_size += info.size;
+        }
+    }
+    crate::blockdev::ensure_fits(ui, raw_name, image_size, &partition_path)?;
+    if opts.wipe {
+        crate::blockdev::discard_all(ui, &partition_path)?;
+    }
+
     let device_file = OpenOptions::new()
         .read(true) 
         .write(true)
@@ -67,95 +91,614 @@ pub fn flash_sparse(
         .context(format!("Failed to open partition {}", partition_path))?;
         
     let mut writer = BufWriter::with_capacity(16 * 1024 * 1024, device_file);
+    let mut written = WrittenRanges::default();
+    let mut diff = opts.diff.then(DiffState::default);
 
-    if is_range {
-        for i in start..=end {
-            let entry_name = format!("{}.{}", zip_base, i);
+    for (entry_name, offset) in entry_names.iter().zip(offsets) {
+        if entry_names.len() > 1 {
             ui.ui_print(&format!("  - Processing {}...", entry_name))?;
-
-            writer.flush()?;
-            writer.seek(SeekFrom::Start(0))?;
-
-            let mut entry = archive.by_name(&entry_name).context("Chunk not found")?;
-            write_sparse(&mut entry, &mut writer)?;
         }
 
-    } else {
-        let mut entry = archive.by_name(&zip_base)?;
-        write_sparse(&mut entry, &mut writer)?;
+        writer.flush()?;
+        writer.seek(SeekFrom::Start(offset))?;
+
+        let mut entry = Abortable(archive.by_name(entry_name).context("Chunk not found")?);
+        write_sparse(&mut entry, &mut writer, offset, &mut written, opts.holes, diff.as_mut())
+            .context(format!("Failed to flash {} to {}", entry_name, partition_path))?;
     }
+    fill_holes(&mut writer, &written, opts.holes, image_size)
+        .context(format!("Failed to clear unused blocks of {}", partition_path))?;
     
-    writer.flush()?;
+    writer.flush().map_err(out_of_space).context(format!("Failed to write {}", partition_path))?;
+    writer.get_ref().sync_all().context(format!("Failed to sync {}", partition_path))?;
+    if let Some(diff) = diff {
+        ui.ui_print(&diff.stats.summary())?;
+    }
     
     Ok(())
 }
 
-fn write_sparse<R: Read, W: Write + Seek>(reader: &mut R, writer: &mut W) -> Result<()> {
+/// Finds split sparse images for `base`, either `<base>.<N>` (`super.img.000`,
+/// `super.img.1`, ...) or Motorola-style `<base>_sparsechunk.<N>`, sorted by N.
+fn find_chunks(archive: &mut ZipArchive<File>, base: &str) -> Result<Vec<(usize, String)>> {
+    let moto_base = format!("{}_sparsechunk", base);
+    let mut plain = Vec::new();
+    let mut moto = Vec::new();
+
+    for name in archive.file_names() {
+        for (prefix, found) in [(base, &mut plain), (moto_base.as_str(), &mut moto)] {
+            if let Some(suffix) = name.strip_prefix(prefix)
+                && let Some(idx_str) = suffix.strip_prefix('.')
+                && !idx_str.is_empty()
+                && idx_str.chars().all(|c| c.is_ascii_digit())
+                && let Ok(idx) = idx_str.parse::<usize>()
+            {
+                found.push((idx, name.to_string()));
+            }
+        }
+    }
+
+    if !plain.is_empty() && !moto.is_empty() {
+        bail!("Both {}.N and {}.N chunks found, refusing to guess", base, moto_base);
+    }
+    let mut chunks = if plain.is_empty() { moto } else { plain };
+    chunks.sort();
+    for pair in chunks.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            bail!("Duplicate chunk index: {} and {}", pair[0].1, pair[1].1);
+        }
+    }
+    Ok(chunks)
+}
+
+struct SparseInfo {
+    /// Expanded size (`total_blks * blk_sz`).
+    size: u64,
+    /// Whether the first chunk is DONT_CARE.
+    leading_skip: bool,
+}
+
+fn read_sparse_info<R: Read>(reader: &mut R) -> Result<SparseInfo> {
     let magic = reader.read_u32::<LittleEndian>()?;
     if magic != SPARSE_HEADER_MAGIC { bail!("Invalid sparse magic: {:x}", magic); }
 
     let _major = reader.read_u16::<LittleEndian>()?;
     let _minor = reader.read_u16::<LittleEndian>()?;
     let file_hdr_sz = reader.read_u16::<LittleEndian>()?;
-    let chunk_hdr_sz = reader.read_u16::<LittleEndian>()?;
+    let _chunk_hdr_sz = reader.read_u16::<LittleEndian>()?;
     let blk_sz = reader.read_u32::<LittleEndian>()?;
-    let _total_blks = reader.read_u32::<LittleEndian>()?;
+    let total_blks = reader.read_u32::<LittleEndian>()?;
     let total_chunks = reader.read_u32::<LittleEndian>()?;
     let _crc = reader.read_u32::<LittleEndian>()?;
 
+    let mut leading_skip = false;
+    if total_chunks > 0 {
+        io::copy(&mut reader.take(file_hdr_sz.saturating_sub(28) as u64), &mut io::sink())?;
+        leading_skip = reader.read_u16::<LittleEndian>()? == CHUNK_TYPE_DONT_CARE;
+    }
+
+    Ok(SparseInfo {
+        size: total_blks as u64 * blk_sz as u64,
+        leading_skip,
+    })
+}
+
+/// Byte ranges of the partition already written by earlier chunks, used to
+/// catch split images whose chunks overlap and to find the holes left over.
+#[derive(Default)]
+struct WrittenRanges {
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl WrittenRanges {
+    fn claim(&mut self, start: u64, len: u64) -> Result<()> {
+        if len == 0 { return Ok(()); }
+        let end = start + len;
+
+        let mut merged_start = start;
+        if let Some((&s, &e)) = self.ranges.range(..end).next_back() {
+            if e > start {
+                bail!("writes {:#x}..{:#x}, which overlaps data already written at {:#x}..{:#x}", start, end, s, e);
+            }
+            if e == start {
+                self.ranges.remove(&s);
+                merged_start = s;
+            }
+        }
+        let mut merged_end = end;
+        if let Some(next_end) = self.ranges.remove(&end) {
+            merged_end = next_end;
+        }
+        self.ranges.insert(merged_start, merged_end);
+        Ok(())
+    }
+
+    /// Parts of `start..start + len` that haven't been written yet.
+    fn gaps(&self, start: u64, len: u64) -> Vec<(u64, u64)> {
+        let end = start + len;
+        let first = self.ranges.range(..=start).next_back().map_or(start, |(&s, _)| s);
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for (&s, &e) in self.ranges.range(first..end) {
+            if s > pos {
+                gaps.push((pos, s - pos));
+            }
+            pos = pos.max(e);
+        }
+        if pos < end {
+            gaps.push((pos, end - pos));
+        }
+        gaps
+    }
+}
+
+/// Discards or zeroes everything in the first `size` bytes that no chunk
+/// wrote. Done once after the last chunk: in absolute sparsechunks the
+/// DONT_CARE chunks of each one cover the blocks all the others write.
+fn fill_holes(writer: &mut BufWriter<File>, written: &WrittenRanges, holes: HoleMode, size: u64) -> Result<()> {
+    if holes == HoleMode::Seek {
+        return Ok(());
+    }
+    let zero_buf = vec![0u8; 1024 * 1024];
+    for (start, len) in written.gaps(0, size) {
+        writer.seek(SeekFrom::Start(start))?;
+        write_hole(writer, holes, start, len, &zero_buf)?;
+    }
+    Ok(())
+}
+
+fn write_sparse<R: Read>(
+    reader: &mut R,
+    writer: &mut BufWriter<File>,
+    offset: u64,
+    written: &mut WrittenRanges,
+    holes: HoleMode,
+    mut diff: Option<&mut DiffState>,
+) -> Result<()> {
+    let magic = reader.read_u32::<LittleEndian>()?;
+    if magic != SPARSE_HEADER_MAGIC { bail!("Invalid sparse magic: {:x}", magic); }
+
+    let _major = reader.read_u16::<LittleEndian>()?;
+    let _minor = reader.read_u16::<LittleEndian>()?;
+    let file_hdr_sz = reader.read_u16::<LittleEndian>()?;
+    let chunk_hdr_sz = reader.read_u16::<LittleEndian>()?;
+    let blk_sz = reader.read_u32::<LittleEndian>()?;
+    let total_blks = reader.read_u32::<LittleEndian>()?;
+    let total_chunks = reader.read_u32::<LittleEndian>()?;
+    let image_crc = reader.read_u32::<LittleEndian>()?;
+
     let bytes_read_so_far = 28; 
+    if file_hdr_sz < bytes_read_so_far { bail!("Invalid sparse header size: {}", file_hdr_sz); }
+    if chunk_hdr_sz < CHUNK_HEADER_SIZE { bail!("Invalid sparse chunk header size: {}", chunk_hdr_sz); }
+    if blk_sz == 0 || blk_sz % 4 != 0 { bail!("Invalid sparse block size: {}", blk_sz); }
     if file_hdr_sz > bytes_read_so_far {
         io::copy(&mut reader.take((file_hdr_sz - bytes_read_so_far) as u64), &mut io::sink())?;
     }
 
     let zero_buf = vec![0u8; 1024 * 1024];
+    let zero_block = vec![0u8; blk_sz as usize];
+    let mut crc = ImageCrc::new();
+    let mut blocks_written: u64 = 0;
 
-    for _ in 0..total_chunks {
+    for idx in 1..=total_chunks {
         let chunk_type = reader.read_u16::<LittleEndian>()?;
         let _reserved = reader.read_u16::<LittleEndian>()?;
         let chunk_sz = reader.read_u32::<LittleEndian>()?; 
         let total_sz = reader.read_u32::<LittleEndian>()?; 
-        let data_sz = (total_sz as u64) - (chunk_hdr_sz as u64);
+        if chunk_hdr_sz > CHUNK_HEADER_SIZE {
+            io::copy(&mut reader.take((chunk_hdr_sz - CHUNK_HEADER_SIZE) as u64), &mut io::sink())?;
+        }
         let output_sz = (chunk_sz as u64) * (blk_sz as u64);
 
+        let expected_sz = match chunk_type {
+            CHUNK_TYPE_RAW => chunk_hdr_sz as u64 + output_sz,
+            CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => chunk_hdr_sz as u64 + 4,
+            CHUNK_TYPE_DONT_CARE => chunk_hdr_sz as u64,
+            _ => bail!("Chunk {}/{}: unknown chunk type {:#06x}", idx, total_chunks, chunk_type),
+        };
+        if total_sz as u64 != expected_sz {
+            bail!("Chunk {}/{}: total_sz is {}, expected {}", idx, total_chunks, total_sz, expected_sz);
+        }
+        if chunk_type == CHUNK_TYPE_CRC32 && chunk_sz != 0 {
+            bail!("Chunk {}/{}: CRC32 chunk covers {} blocks, expected 0", idx, total_chunks, chunk_sz);
+        }
+
+        let chunk_start = offset + blocks_written * blk_sz as u64;
+        blocks_written += chunk_sz as u64;
+        if blocks_written > total_blks as u64 {
+            bail!("Chunk {}/{}: chunks exceed total_blks ({})", idx, total_chunks, total_blks);
+        }
+        if chunk_type == CHUNK_TYPE_RAW || chunk_type == CHUNK_TYPE_FILL {
+            written.claim(chunk_start, output_sz)
+                .map_err(|e| anyhow::anyhow!("Chunk {}/{}: {}", idx, total_chunks, e))?;
+        }
+
         match chunk_type {
-            CHUNK_TYPE_RAW => { io::copy(&mut reader.take(output_sz), writer)?; },
+            CHUNK_TYPE_RAW => {
+                let mut raw = reader.take(output_sz);
+                let mut buf = vec![0u8; zero_buf.len()];
+                let mut pos = chunk_start;
+                loop {
+                    let n = fill_buf(&mut raw, &mut buf)?;
+                    if n == 0 { break; }
+                    crc.update(&buf[..n]);
+                    write_data(writer, pos, &buf[..n], diff.as_deref_mut())?;
+                    pos += n as u64;
+                }
+                if raw.limit() != 0 {
+                    bail!("Chunk {}/{}: unexpected end of file", idx, total_chunks);
+                }
+            },
             CHUNK_TYPE_FILL => {
                 let fill_val = reader.read_u32::<LittleEndian>()?;
                 if fill_val == 0 {
-                    seek_or_write(writer, output_sz, &zero_buf)?;
+                    crc.repeat(&zero_block, chunk_sz as u64);
+                    // Unlike DONT_CARE, these blocks must read back as zeros.
+                    let zero_holes = if holes == HoleMode::Seek { HoleMode::Seek } else { HoleMode::ZeroOut };
+                    write_hole(writer, zero_holes, chunk_start, output_sz, &zero_buf)?;
                 } else {
-                    let mut fill_block = vec![0u8; blk_sz as usize];
-                    let fb = fill_val.to_le_bytes();
-                    for i in 0..(blk_sz as usize / 4) {
-                        fill_block[i*4..i*4+4].copy_from_slice(&fb);
+                    let fill_block: Vec<u8> = fill_val.to_le_bytes().repeat(blk_sz as usize / 4);
+                    crc.repeat(&fill_block, chunk_sz as u64);
+
+                    let blocks_per_write = (zero_buf.len() / blk_sz as usize).max(1) as u64;
+                    let fill_buf = fill_block.repeat(blocks_per_write.min(chunk_sz as u64) as usize);
+                    let mut pos = chunk_start;
+                    let end = chunk_start + output_sz;
+                    while pos < end {
+                        let n = (end - pos).min(fill_buf.len() as u64) as usize;
+                        write_data(writer, pos, &fill_buf[..n], diff.as_deref_mut())?;
+                        pos += n as u64;
                     }
-                    for _ in 0..chunk_sz { writer.write_all(&fill_block)?; }
                 }
             },
-            CHUNK_TYPE_DONT_CARE => { seek_or_write(writer, output_sz, &zero_buf)?; },
-            CHUNK_TYPE_CRC32 => { io::copy(&mut reader.take(data_sz), &mut io::sink())?; },
-            _ => { io::copy(&mut reader.take(data_sz), &mut io::sink())?; }
+            CHUNK_TYPE_DONT_CARE => {
+                // Discarded/zeroed by `fill_holes` once every chunk is written.
+                crc.repeat(&zero_block, chunk_sz as u64);
+                writer.seek(SeekFrom::Start(chunk_start + output_sz))?;
+            },
+            _ => {
+                let expected = reader.read_u32::<LittleEndian>()?;
+                let actual = crc.value();
+                if expected != actual {
+                    bail!("Chunk {}/{}: CRC32 mismatch (expected {:08x}, got {:08x})", idx, total_chunks, expected, actual);
+                }
+            },
         }
     }
+
+    if blocks_written != total_blks as u64 {
+        bail!("Sparse image has {} blocks, header says {}", blocks_written, total_blks);
+    }
+    if image_crc != 0 && image_crc != crc.value() {
+        bail!("Sparse image CRC32 mismatch (expected {:08x}, got {:08x})", image_crc, crc.value());
+    }
+    Ok(())
+}
+
+/// Running CRC32 of the expanded image, checked against CRC32 chunks and the
+/// header checksum. Repeated blocks (fill and don't-care) are folded in with
+/// `combine` instead of hashing every byte.
+struct ImageCrc {
+    hasher: crc32fast::Hasher,
+}
+
+impl ImageCrc {
+    fn new() -> Self {
+        ImageCrc { hasher: crc32fast::Hasher::new() }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn repeat(&mut self, block: &[u8], mut count: u64) {
+        let mut pow = crc32fast::Hasher::new();
+        pow.update(block);
+        let mut acc = crc32fast::Hasher::new();
+        while count > 0 {
+            if count & 1 == 1 { acc.combine(&pow); }
+            let doubled = pow.clone();
+            pow.combine(&doubled);
+            count >>= 1;
+        }
+        self.hasher.combine(&acc);
+    }
+
+    fn value(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+/// Compare buffer and counters for `--diff` mode.
+#[derive(Default)]
+struct DiffState {
+    stats: DiffStats,
+    cmp: Vec<u8>,
+}
+
+/// Writes `data` at `pos`, which must be the writer's current position. In
+/// `--diff` mode, `data` is only written if it differs from the partition.
+fn write_data(writer: &mut BufWriter<File>, pos: u64, data: &[u8], diff: Option<&mut DiffState>) -> Result<()> {
+    match diff {
+        Some(diff) => {
+            writer.flush()?;
+            write_if_changed(writer.get_ref(), pos, data, &mut diff.cmp, &mut diff.stats)?;
+            writer.seek(SeekFrom::Start(pos + data.len() as u64))?;
+        },
+        None => writer.write_all(data).map_err(out_of_space)?,
+    }
     Ok(())
 }
 
+/// Reads until `buf` is full or the reader is exhausted.
+fn fill_buf<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 { break; }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Skips `len` bytes at `start` according to `holes`, falling back to
+/// `seek_or_write` when the ioctl isn't supported (e.g. regular files).
+pub fn write_hole(writer: &mut BufWriter<File>, holes: HoleMode, start: u64, len: u64, buf: &[u8]) -> Result<()> {
+    let done = match holes {
+        HoleMode::Seek => false,
+        HoleMode::Discard => {
+            writer.flush()?;
+            crate::blockdev::discard(writer.get_ref(), start, len).is_ok()
+        },
+        HoleMode::ZeroOut => {
+            writer.flush()?;
+            crate::blockdev::zero_out(writer.get_ref(), start, len).is_ok()
+        },
+    };
+    if done {
+        writer.seek(SeekFrom::Start(start + len))?;
+        return Ok(());
+    }
+
+    if holes == HoleMode::ZeroOut {
+        let mut remaining = len;
+        while remaining > 0 {
+            let to_write = std::cmp::min(remaining, buf.len() as u64) as usize;
+            writer.write_all(&buf[..to_write]).map_err(out_of_space)?;
+            remaining -= to_write as u64;
+        }
+        writer.flush().map_err(out_of_space)?;
+        return Ok(());
+    }
+    seek_or_write(writer, len, buf)
+}
+
 fn seek_or_write<W: Write + Seek>(writer: &mut W, mut bytes: u64, buf: &[u8]) -> Result<()> {
-    if writer.flush().is_ok() {
-        if writer.seek(SeekFrom::Current(bytes as i64)).is_ok() { return Ok(()); }
+    if writer.flush().is_ok() && writer.seek(SeekFrom::Current(bytes as i64)).is_ok() {
+        return Ok(());
     }
     while bytes > 0 {
         let to_write = std::cmp::min(bytes, buf.len() as u64) as usize;
-        if let Err(e) = writer.write_all(&buf[..to_write]) {
-             if e.kind() == ErrorKind::WriteZero || e.raw_os_error() == Some(28) { return Ok(()); }
-             return Err(e.into());
-        }
-        if let Err(e) = writer.flush() {
-            if e.kind() == ErrorKind::WriteZero || e.raw_os_error() == Some(28) { return Ok(()); }
-            return Err(e.into());
-        }
+        writer.write_all(&buf[..to_write]).map_err(out_of_space)?;
         bytes -= to_write as u64;
     }
+    writer.flush().map_err(out_of_space)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Turns a short write or ENOSPC into an error that says the target is full.
+fn out_of_space(e: io::Error) -> anyhow::Error {
+    if e.kind() == ErrorKind::WriteZero || e.raw_os_error() == Some(libc::ENOSPC) {
+        anyhow::anyhow!("no space left on the partition ({})", e)
+    } else {
+        e.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 4096;
+
+    enum Chunk<'a> {
+        Raw(&'a [u8]),
+        Fill(u32, u32),
+        DontCare(u32),
+        Crc(u32),
+    }
+
+    fn sparse_image(chunks: &[Chunk], image_crc: u32) -> Vec<u8> {
+        let blocks: u32 = chunks.iter().map(|c| match c {
+            Chunk::Raw(data) => (data.len() / BLOCK) as u32,
+            Chunk::Fill(_, n) | Chunk::DontCare(n) => *n,
+            Chunk::Crc(_) => 0,
+        }).sum();
+        let mut out = Vec::new();
+        out.extend_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        out.extend_from_slice(&[1, 0, 0, 0]);
+        out.extend_from_slice(&28u16.to_le_bytes());
+        out.extend_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+        for value in [BLOCK as u32, blocks, chunks.len() as u32, image_crc] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let header = |out: &mut Vec<u8>, kind: u16, blocks: u32, payload: usize| {
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(&blocks.to_le_bytes());
+            out.extend_from_slice(&(CHUNK_HEADER_SIZE as u32 + payload as u32).to_le_bytes());
+        };
+        for chunk in chunks {
+            match chunk {
+                Chunk::Raw(data) => {
+                    header(&mut out, CHUNK_TYPE_RAW, (data.len() / BLOCK) as u32, data.len());
+                    out.extend_from_slice(data);
+                },
+                Chunk::Fill(value, n) => {
+                    header(&mut out, CHUNK_TYPE_FILL, *n, 4);
+                    out.extend_from_slice(&value.to_le_bytes());
+                },
+                Chunk::DontCare(n) => header(&mut out, CHUNK_TYPE_DONT_CARE, *n, 0),
+                Chunk::Crc(crc) => {
+                    header(&mut out, CHUNK_TYPE_CRC32, 0, 4);
+                    out.extend_from_slice(&crc.to_le_bytes());
+                },
+            }
+        }
+        out
+    }
+
+    /// Writes each image at offset 0 of a temp file holding `initial`, like
+    /// absolute sparsechunks, and returns the file contents.
+    fn flash(name: &str, images: &[Vec<u8>], holes: HoleMode, initial: &[u8]) -> Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("sparse-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, initial)?;
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut writer = BufWriter::new(file);
+        let mut written = WrittenRanges::default();
+        let size = images.iter().map(|image| u32::from_le_bytes(image[16..20].try_into().unwrap()) as u64).max();
+        let result = images
+            .iter()
+            .try_for_each(|image| {
+                writer.seek(SeekFrom::Start(0))?;
+                write_sparse(&mut image.as_slice(), &mut writer, 0, &mut written, holes, None)
+            })
+            .and_then(|()| fill_holes(&mut writer, &written, holes, size.unwrap_or(0) * BLOCK as u64));
+        drop(writer);
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path)?;
+        result.map(|()| data.unwrap())
+    }
+
+    #[test]
+    fn written_ranges_merge_and_reject_overlaps() {
+        let mut written = WrittenRanges::default();
+        written.claim(0, 10).unwrap();
+        written.claim(20, 10).unwrap();
+        written.claim(10, 10).unwrap();
+        assert_eq!(written.ranges.iter().collect::<Vec<_>>(), [(&0, &30)]);
+
+        assert!(written.claim(25, 10).is_err());
+        assert!(written.claim(29, 1).is_err());
+        written.claim(30, 0).unwrap();
+        written.claim(40, 5).unwrap();
+        assert_eq!(written.ranges.len(), 2);
+    }
+
+    #[test]
+    fn written_ranges_gaps() {
+        let mut written = WrittenRanges::default();
+        assert_eq!(written.gaps(0, 50), [(0, 50)]);
+        written.claim(10, 10).unwrap();
+        written.claim(30, 10).unwrap();
+        assert_eq!(written.gaps(0, 50), [(0, 10), (20, 10), (40, 10)]);
+        assert_eq!(written.gaps(15, 20), [(20, 10)]);
+        assert!(written.gaps(30, 10).is_empty());
+        assert_eq!(written.gaps(35, 10), [(40, 5)]);
+    }
+
+    #[test]
+    fn image_crc_repeat_matches_plain_crc() {
+        let block: Vec<u8> = (0..BLOCK).map(|i| i as u8).collect();
+        for count in [0, 1, 2, 7, 64] {
+            let mut crc = ImageCrc::new();
+            crc.update(b"head");
+            crc.repeat(&block, count);
+            crc.update(b"tail");
+
+            let mut plain = crc32fast::Hasher::new();
+            plain.update(b"head");
+            plain.update(&block.repeat(count as usize));
+            plain.update(b"tail");
+            assert_eq!(crc.value(), plain.finalize(), "count {}", count);
+        }
+    }
+
+    #[test]
+    fn writes_chunks_and_checks_crc() {
+        let raw = vec![0xab; BLOCK];
+        let mut expected = raw.clone();
+        expected.extend(0x1234_5678u32.to_le_bytes().repeat(2 * BLOCK / 4));
+        let partial_crc = crc32fast::hash(&expected);
+        let crc = crc32fast::hash(&[&expected[..], &[0; BLOCK]].concat());
+
+        // The DONT_CARE block is skipped, or zeroed with --zeroout.
+        let chunks = [Chunk::Raw(&raw), Chunk::Fill(0x1234_5678, 2), Chunk::Crc(partial_crc), Chunk::DontCare(1)];
+        for (holes, tail) in [(HoleMode::Seek, 0xff), (HoleMode::ZeroOut, 0)] {
+            let out = flash("crc", &[sparse_image(&chunks, crc)], holes, &[0xff; 4 * BLOCK]).unwrap();
+            assert_eq!(out[..3 * BLOCK], expected);
+            assert!(out[3 * BLOCK..].iter().all(|&byte| byte == tail));
+        }
+
+        let image = sparse_image(&[Chunk::Raw(&raw), Chunk::Crc(partial_crc)], 0);
+        assert!(flash("crc-chunk", &[image], HoleMode::Seek, &[]).is_err());
+        let image = sparse_image(&[Chunk::Raw(&raw)], 1);
+        assert!(flash("crc-header", &[image], HoleMode::Seek, &[]).is_err());
+    }
+
+    #[test]
+    fn crc_chunk_covers_no_blocks() {
+        let raw = vec![1; BLOCK];
+        let mut image = sparse_image(&[Chunk::Raw(&raw), Chunk::Crc(crc32fast::hash(&raw))], 0);
+        image[16..20].copy_from_slice(&2u32.to_le_bytes());
+        let crc_chunk = 28 + 12 + BLOCK;
+        image[crc_chunk + 4..crc_chunk + 8].copy_from_slice(&1u32.to_le_bytes());
+        assert!(flash("crc-blocks", &[image], HoleMode::Seek, &[]).is_err());
+    }
+
+    #[test]
+    fn block_count_must_match_header() {
+        let raw = vec![1; 2 * BLOCK];
+        for total_blks in [1u32, 3] {
+            let mut image = sparse_image(&[Chunk::Raw(&raw)], 0);
+            image[16..20].copy_from_slice(&total_blks.to_le_bytes());
+            assert!(flash("blocks", &[image], HoleMode::Seek, &[]).is_err(), "total_blks {}", total_blks);
+        }
+    }
+
+    #[test]
+    fn absolute_chunks_keep_earlier_data() {
+        let (a, b) = (vec![0xaa; BLOCK], vec![0xbb; BLOCK]);
+        let first = sparse_image(&[Chunk::Raw(&a), Chunk::DontCare(2)], 0);
+        let second = sparse_image(&[Chunk::DontCare(1), Chunk::Raw(&b), Chunk::DontCare(1)], 0);
+
+        for (holes, tail) in [(HoleMode::Seek, 0xff), (HoleMode::ZeroOut, 0)] {
+            let out = flash("absolute", &[first.clone(), second.clone()], holes, &[0xff; 3 * BLOCK]).unwrap();
+            assert_eq!(out[..BLOCK], a);
+            assert_eq!(out[BLOCK..2 * BLOCK], b);
+            assert!(out[2 * BLOCK..].iter().all(|&byte| byte == tail));
+        }
+
+        let overlapping = sparse_image(&[Chunk::Raw(&b), Chunk::DontCare(2)], 0);
+        assert!(flash("overlap", &[first, overlapping], HoleMode::Seek, &[]).is_err());
+    }
+
+    #[test]
+    fn zeroing_a_full_target_reports_no_space() {
+        let Ok(file) = OpenOptions::new().write(true).open("/dev/full") else { return };
+        let mut writer = BufWriter::new(file);
+        let err = write_hole(&mut writer, HoleMode::ZeroOut, 0, BLOCK as u64, &[0; BLOCK]).unwrap_err();
+        assert!(err.to_string().contains("no space left"), "{:#}", err);
+    }
+
+    #[test]
+    fn dont_care_is_left_for_fill_holes() {
+        let path = std::env::temp_dir().join(format!("sparse-test-{}-deferred", std::process::id()));
+        std::fs::write(&path, [0xff; 3 * BLOCK]).unwrap();
+        let mut writer = BufWriter::new(OpenOptions::new().read(true).write(true).open(&path).unwrap());
+        let mut written = WrittenRanges::default();
+        let image = sparse_image(&[Chunk::Raw(&[0xaa; BLOCK]), Chunk::DontCare(2)], 0);
+        write_sparse(&mut image.as_slice(), &mut writer, 0, &mut written, HoleMode::ZeroOut, None).unwrap();
+        writer.flush().unwrap();
+        assert!(std::fs::read(&path).unwrap()[BLOCK..].iter().all(|&byte| byte == 0xff));
+
+        fill_holes(&mut writer, &written, HoleMode::ZeroOut, 2 * BLOCK as u64).unwrap();
+        writer.flush().unwrap();
+        let out = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(out[..BLOCK].iter().all(|&byte| byte == 0xaa));
+        assert!(out[BLOCK..2 * BLOCK].iter().all(|&byte| byte == 0));
+        assert!(out[2 * BLOCK..].iter().all(|&byte| byte == 0xff));
+    }
+}
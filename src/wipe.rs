@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, bail};
+
+use crate::recovery::RecoveryUI;
+use crate::func::{getprop, slot_partition_path};
+use crate::mount::{self, Mounts};
+
+/// Where the recovery keeps its fstab; `{hw}` is `ro.hardware`.
+const FSTABS: [&str; 5] = [
+    "/etc/recovery.fstab",
+    "/etc/twrp.fstab",
+    "/system/etc/recovery.fstab",
+    "/vendor/etc/fstab.{hw}",
+    "/fstab.{hw}",
+];
+
+/// Left behind by vold on file-based encryption.
+const FBE_MARKERS: [&str; 2] = ["/data/unencrypted", "/data/misc/vold/user_keys"];
+
+fn abort(ui: &mut RecoveryUI, msg: &str) -> Result<()> {
+    let _ = ui.ui_print(msg);
+    bail!("{}", msg);
+}
+
+/// Checks `--confirm=<var>`: without it the command always runs, with it
+/// only if the script set `<var>` to `1`, `yes` or `true` (e.g. from `prompt`).
+fn confirmed(ui: &mut RecoveryUI, command: &str, args: &[String], vars: &HashMap<String, String>) -> Result<bool> {
+    let Some(var) = args.iter().find_map(|a| a.strip_prefix("--confirm=")) else {
+        return Ok(true);
+    };
+    let value = vars.get(var).map(String::as_str).unwrap_or("");
+    let ok = matches!(value.to_lowercase().as_str(), "1" | "yes" | "true");
+    if !ok {
+        ui.ui_print(&format!("Skipping {}: not confirmed ({} is '{}')", command, var, value))?;
+    }
+    Ok(ok)
+}
+
+/// Filesystem of `mountpoint` in the recovery fstab.
+fn fstab_fs_type(mountpoint: &str) -> Option<String> {
+    let hardware = getprop("ro.hardware");
+    FSTABS
+        .iter()
+        .map(|f| f.replace("{hw}", &hardware))
+        .filter_map(|f| fs::read_to_string(f).ok())
+        .flat_map(|content| {
+            content
+                .lines()
+                .filter(|l| !l.trim_start().starts_with('#'))
+                .filter_map(|l| {
+                    let fields: Vec<&str> = l.split_whitespace().collect();
+                    // Android fstab: <src> <mnt> <type> ..., old recovery.fstab: <mnt> <type> <src>
+                    match fields.as_slice() {
+                        [_, mnt, fstype, ..] if *mnt == mountpoint => Some(fstype.to_string()),
+                        [mnt, fstype, ..] if *mnt == mountpoint => Some(fstype.to_string()),
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .find(|t| t == "ext4" || t == "f2fs")
+}
+
+fn is_fbe() -> bool {
+    getprop("ro.crypto.type") == "file" || FBE_MARKERS.iter().any(|p| Path::new(p).exists())
+}
+
+/// Unmounts every mount of `device` (`/data` is usually also bind mounted
+/// as `/sdcard`).
+fn unmount_device(ui: &mut RecoveryUI, mounts: &mut Mounts, device: &str) -> Result<()> {
+    for _ in 0..8 {
+        match mount::mount_point_of(device) {
+            Some(mountpoint) => mounts.unmount(ui, &mountpoint)?,
+            None => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// `wipe_data [--keep-media] [--confirm=<var>]`: deletes everything in
+/// `/data` (except `/data/media` with `--keep-media`). The filesystem and
+/// `/metadata` are kept, so it works on a decrypted `/data` only.
+pub fn wipe_data(ui: &mut RecoveryUI, mounts: &mut Mounts, args: &[String], vars: &HashMap<String, String>) -> Result<()> {
+    let mut keep_media = false;
+    for arg in args {
+        match arg.as_str() {
+            "--keep-media" => keep_media = true,
+            a if a.starts_with("--confirm=") => {},
+            _ => bail!("Invalid wipe_data option: {}", arg),
+        }
+    }
+    if !confirmed(ui, "wipe_data", args, vars)? {
+        return Ok(());
+    }
+
+    let mounted_here = !mount::is_mounted("/data");
+    if mounted_here {
+        let target = ["userdata".to_string(), "/data".to_string()];
+        if let Err(e) = mounts.mount(ui, &target) {
+            return abort(ui, &format!("Can't mount /data ({:#}). If it is encrypted, use format_data instead. Aborting...", e));
+        }
+    }
+    if keep_media && is_fbe() {
+        // New user keys can't decrypt the old /data/media, Android would fail to boot.
+        return abort(ui, "--keep-media is not possible with file-based encryption, use format_data instead. Aborting...");
+    }
+
+    ui.ui_print(if keep_media { "Wiping /data (keeping /data/media)..." } else { "Wiping /data..." })?;
+    let mut failed = 0;
+    for entry in fs::read_dir("/data")?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if (keep_media && name == "media") || name == "lost+found" {
+            continue;
+        }
+        let path = entry.path();
+        let result = if entry.file_type()?.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        if let Err(e) = result {
+            ui.ui_print(&format!("Warning: failed to delete {}: {}", path.display(), e))?;
+            failed += 1;
+        }
+    }
+
+    if mounted_here {
+        mounts.unmount(ui, "/data")?;
+    }
+    if failed > 0 {
+        return abort(ui, &format!("{} entries in /data could not be deleted. Aborting...", failed));
+    }
+    ui.ui_print("Data wiped")?;
+    Ok(())
+}
+
+/// `format_data [--fs=<ext4|f2fs>] [--confirm=<var>]`: formats `userdata` and
+/// wipes `metadata` (it holds the metadata encryption key of the old `/data`),
+/// like "Format Data" in fastboot/recovery. Internal storage is lost.
+pub fn format_data(ui: &mut RecoveryUI, mounts: &mut Mounts, args: &[String], vars: &HashMap<String, String>) -> Result<()> {
+    let mut forced_fs = None;
+    for arg in args {
+        if let Some(fstype) = arg.strip_prefix("--fs=") {
+            forced_fs = Some(fstype.to_string());
+        } else if !arg.starts_with("--confirm=") {
+            bail!("Invalid format_data option: {}", arg);
+        }
+    }
+    if !confirmed(ui, "format_data", args, vars)? {
+        return Ok(());
+    }
+
+    // The raw partition, not the dm-default-key mapping of it.
+    let userdata = slot_partition_path("userdata", "");
+    if !Path::new(&userdata).exists() {
+        return abort(ui, "userdata partition not found. Aborting...");
+    }
+    let metadata = slot_partition_path("metadata", "");
+
+    // With metadata encryption /data is mounted from a dm device on top of userdata.
+    if let Some(device) = mount::device_of("/data") {
+        unmount_device(ui, mounts, &device)?;
+    }
+    unmount_device(ui, mounts, &userdata)?;
+
+    let data_fs = forced_fs
+        .or_else(|| mount::detect_fs(&userdata).map(String::from))
+        .or_else(|| fstab_fs_type("/data"))
+        .unwrap_or_else(|| "f2fs".to_string());
+    mount::format_device(ui, &userdata, &data_fs, true)?;
+
+    if Path::new(&metadata).exists() {
+        if let Some(device) = mount::device_of("/metadata") {
+            unmount_device(ui, mounts, &device)?;
+        }
+        unmount_device(ui, mounts, &metadata)?;
+        let metadata_fs = mount::detect_fs(&metadata)
+            .map(String::from)
+            .or_else(|| fstab_fs_type("/metadata"))
+            .unwrap_or_else(|| "ext4".to_string());
+        mount::format_device(ui, &metadata, &metadata_fs, false)?;
+    }
+
+    ui.ui_print("Data formatted")?;
+    Ok(())
+}